@@ -0,0 +1,308 @@
+use super::CritKey;
+use std::convert::TryFrom;
+
+// Nodes live side by side in one vector and point at each other by index. Freed slots are chained
+// together through `Free` and handed out again before the vector grows.
+enum Slot<K,V> {
+    Leaf ( K, V ),
+    Internal ( [u32; 2], usize ),
+    Free ( Option<u32> ),
+}
+
+/// A crit-bit tree like `CritBit`, but with every node stored in a single `Vec` and linked by
+/// index, so lookups stay within one allocation and inserts reuse the slots removals free up.
+pub struct CritBitArena<K,V> where K: CritKey {
+    slots: Vec<Slot<K,V>>,
+    root: Option<u32>,
+    free: Option<u32>,
+    len: usize,
+}
+
+// Where a node is linked from: the root, or one side of an internal node.
+#[derive(Clone, Copy)]
+enum Link {
+    Root,
+    Child ( u32, usize ),
+}
+
+impl<K,V> CritBitArena<K,V> where K: CritKey {
+    pub fn new() -> CritBitArena<K,V> {
+        CritBitArena { slots: Vec::new(), root: None, free: None, len: 0 }
+    }
+
+    pub fn len( &self ) -> usize {
+        self.len
+    }
+
+    pub fn is_empty( &self ) -> bool {
+        self.root.is_none()
+    }
+
+    /// The number of nodes the arena can hold without reallocating. Each entry takes two nodes, a
+    /// leaf and the internal node above it, except the first, which takes one.
+    pub fn capacity( &self ) -> usize {
+        self.slots.capacity()
+    }
+
+    /// Reserves room for at least `additional` more nodes, as `Vec::reserve` does. Freed slots are
+    /// reused first, so they count against `additional` too.
+    pub fn reserve( &mut self, additional: usize ) {
+        self.slots.reserve( additional );
+    }
+
+    /// Shrinks the capacity to no less than `min_capacity`, as `Vec::shrink_to` does. Freed slots
+    /// are squeezed out first, so the capacity can go as low as the nodes still in use.
+    pub fn shrink_to( &mut self, min_capacity: usize ) {
+        if self.free.is_some() {
+            self.compact();
+        }
+        self.slots.shrink_to( min_capacity );
+    }
+
+    pub fn clear( &mut self ) {
+        self.slots.clear();
+        self.root = None;
+        self.free = None;
+        self.len = 0;
+    }
+
+    pub fn get( &self, key: &K ) -> Option<&V> {
+        match self.slots[ self.nearest( key )? as usize ] {
+            Slot::Leaf ( ref k, ref v ) if *k == *key => Some( v ),
+            _ => None
+        }
+    }
+
+    pub fn get_mut( &mut self, key: &K ) -> Option<&mut V> {
+        let leaf = self.nearest( key )?;
+        match self.slots[ leaf as usize ] {
+            Slot::Leaf ( ref k, ref mut v ) if *k == *key => Some( v ),
+            _ => None
+        }
+    }
+
+    pub fn contains_key( &self, key: &K ) -> bool {
+        self.get( key ).is_some()
+    }
+
+    /// Inserts `value` for `key`, returning the value it replaces, if any.
+    pub fn insert( &mut self, key: K, value: V ) -> Option<V> {
+        let nearest = match self.nearest( &key ) {
+            Some( nearest ) => nearest,
+            None => {
+                self.root = Some( self.alloc( Slot::Leaf ( key, value ) ) );
+                self.len = 1;
+                return None;
+            }
+        };
+        let crit = match self.slots[ nearest as usize ] {
+            Slot::Leaf ( ref k, ref mut v ) => match k.crit_bit( &key ) {
+                Some( crit ) => crit,
+                None => return Some( std::mem::replace( v, value ) )
+            },
+            _ => unreachable!("Searches always end at a leaf")
+        };
+
+        // Splice in above the first node that splits on a later bit than `crit`
+        let mut link = Link::Root;
+        let mut node = self.root.expect( "We found a nearest key" );
+        while let Slot::Internal ( kids, pos ) = self.slots[ node as usize ] {
+            if pos > crit {
+                break;
+            }
+            let side = key.bit_set( pos ) as usize;
+            link = Link::Child ( node, side );
+            node = kids[ side ];
+        }
+        let side = key.bit_set( crit ) as usize;
+        let leaf = self.alloc( Slot::Leaf ( key, value ) );
+        let mut kids = [ node, node ];
+        kids[ side ] = leaf;
+        let internal = self.alloc( Slot::Internal ( kids, crit ) );
+        self.relink( link, internal );
+        self.len += 1;
+        None
+    }
+
+    /// Removes `key`, returning its value, and frees the slots of its leaf and parent for reuse.
+    pub fn remove( &mut self, key: &K ) -> Option<V> {
+        // How the node is linked in, and its parent and sibling along with how that's linked in
+        let mut link = Link::Root;
+        let mut parent = None;
+        let mut node = self.root?;
+        while let Slot::Internal ( kids, pos ) = self.slots[ node as usize ] {
+            let side = key.bit_set( pos ) as usize;
+            parent = Some( ( link, node, kids[ 1 - side ] ) );
+            link = Link::Child ( node, side );
+            node = kids[ side ];
+        }
+        match self.slots[ node as usize ] {
+            Slot::Leaf ( ref k, _ ) if *k == *key => (),
+            _ => return None
+        }
+
+        match parent {
+            Some( ( above, parent, sibling ) ) => {
+                self.relink( above, sibling );
+                self.release( parent );
+            },
+            None => self.root = None
+        }
+        self.len -= 1;
+        match self.release( node ) {
+            Slot::Leaf ( _, v ) => Some( v ),
+            _ => unreachable!("We just checked that this was a leaf...")
+        }
+    }
+
+    // The leaf a search for `key` ends at, if the tree isn't empty.
+    fn nearest( &self, key: &K ) -> Option<u32> {
+        let mut node = self.root?;
+        while let Slot::Internal ( kids, pos ) = self.slots[ node as usize ] {
+            node = kids[ key.bit_set( pos ) as usize ];
+        }
+        Some( node )
+    }
+
+    // Moves the nodes still in use down over the freed slots, keeping their order, and renumbers the
+    // links between them.
+    fn compact( &mut self ) {
+        let mut remap = Vec::with_capacity( self.slots.len() );
+        let mut used = 0;
+        for slot in self.slots.iter() {
+            remap.push( used as u32 );
+            if ! matches!( *slot, Slot::Free ( _ ) ) {
+                used += 1;
+            }
+        }
+        let slots = std::mem::take( &mut self.slots );
+        self.slots = slots.into_iter()
+            .filter( |slot| ! matches!( *slot, Slot::Free ( _ ) ) )
+            .map( |slot| match slot {
+                Slot::Internal ( kids, pos ) => Slot::Internal ( [ remap[ kids[ 0 ] as usize ], remap[ kids[ 1 ] as usize ] ], pos ),
+                slot => slot
+            } )
+            .collect();
+        self.root = self.root.map( |root| remap[ root as usize ] );
+        self.free = None;
+    }
+
+    fn relink( &mut self, link: Link, node: u32 ) {
+        match link {
+            Link::Root => self.root = Some( node ),
+            Link::Child ( parent, side ) => match self.slots[ parent as usize ] {
+                Slot::Internal ( ref mut kids, _ ) => kids[ side ] = node,
+                _ => unreachable!("Only internal nodes have children")
+            }
+        }
+    }
+
+    fn alloc( &mut self, slot: Slot<K,V> ) -> u32 {
+        match self.free {
+            Some( index ) => {
+                self.free = match std::mem::replace( &mut self.slots[ index as usize ], slot ) {
+                    Slot::Free ( next ) => next,
+                    _ => unreachable!("The free list should only hold free slots")
+                };
+                index
+            },
+            None => {
+                let index = u32::try_from( self.slots.len() ).expect( "An arena holds at most u32::MAX + 1 nodes" );
+                self.slots.push( slot );
+                index
+            }
+        }
+    }
+
+    fn release( &mut self, index: u32 ) -> Slot<K,V> {
+        let slot = std::mem::replace( &mut self.slots[ index as usize ], Slot::Free ( self.free ) );
+        self.free = Some( index );
+        slot
+    }
+}
+
+impl<K: CritKey, V> Default for CritBitArena<K, V> {
+    fn default() -> CritBitArena<K,V> {
+        CritBitArena::new()
+    }
+}
+
+#[test]
+fn matches_boxed_tree() {
+    let mut arena = CritBitArena::new();
+    let mut boxed = ::CritBit::new();
+    for i in 0..10000u32 {
+        let k = i.wrapping_mul( 2654435761 );
+        assert_eq!( arena.insert( k, i ), boxed.insert( k, i ) );
+    }
+    assert_eq!( arena.insert( 0, 7 ), boxed.insert( 0, 7 ) );
+    assert_eq!( arena.len(), boxed.len() );
+    for i in 0..10000u32 {
+        let k = i.wrapping_mul( 2654435761 );
+        assert_eq!( arena.get( &k ), boxed.get( &k ) );
+        assert_eq!( arena.get( &( k ^ 1 ) ), boxed.get( &( k ^ 1 ) ) );
+    }
+    for i in ( 0..10000u32 ).filter( |i| i % 3 == 0 ) {
+        let k = i.wrapping_mul( 2654435761 );
+        assert_eq!( arena.remove( &k ), boxed.remove( &k ) );
+        assert_eq!( arena.remove( &k ), None );
+    }
+    assert_eq!( arena.len(), boxed.len() );
+    for ( k, v ) in boxed.iter() {
+        assert_eq!( arena.get( k ), Some( v ) );
+    }
+}
+
+#[test]
+fn remove_frees_slots() {
+    let mut arena = CritBitArena::new();
+    for k in 0..1000u16 {
+        arena.insert( k, () );
+    }
+    let slots = arena.slots.len();
+    assert_eq!( slots, 1999 );
+    for k in 0..500u16 {
+        assert_eq!( arena.remove( &k ), Some( () ) );
+    }
+    for k in 5000..5500u16 {
+        arena.insert( k, () );
+    }
+    assert_eq!( arena.slots.len(), slots );
+    assert_eq!( arena.len(), 1000 );
+    assert!( ( 500..1000u16 ).chain( 5000..5500 ).all( |k| arena.contains_key( &k ) ) );
+    assert!( ! arena.contains_key( &0 ) );
+
+    while let Some( k ) = ( 500..1000u16 ).chain( 5000..5500 ).find( |k| arena.contains_key( k ) ) {
+        arena.remove( &k );
+    }
+    assert!( arena.is_empty() );
+    arena.insert( 1, () );
+    assert_eq!( arena.get( &1 ), Some( &() ) );
+}
+
+#[test]
+fn capacity_controls() {
+    let mut arena = CritBitArena::new();
+    arena.reserve( 2000 );
+    assert!( arena.capacity() >= 2000 );
+    let reserved = arena.capacity();
+    for k in 0..1000u16 {
+        arena.insert( k.wrapping_mul( 7919 ), k );
+    }
+    assert_eq!( arena.capacity(), reserved );
+
+    for k in ( 0..1000u16 ).filter( |k| k % 4 != 0 ) {
+        arena.remove( &k.wrapping_mul( 7919 ) );
+    }
+    arena.shrink_to( 0 );
+    assert_eq!( arena.slots.len(), 499 );
+    assert!( arena.capacity() < reserved );
+    for k in 0..1000u16 {
+        let expected = if k % 4 == 0 { Some( &k ) } else { None };
+        assert_eq!( arena.get( &k.wrapping_mul( 7919 ) ), expected );
+    }
+
+    arena.insert( 1, 1 );
+    arena.remove( &0 );
+    assert_eq!( ( arena.len(), arena.get( &1 ) ), ( 250, Some( &1 ) ) );
+}
@@ -1,213 +1,4355 @@
+// The original tests compare against bool literals.
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
+
 extern crate num;
 use num::PrimInt;
 
-use std::ops::Add;
+use std::cmp::Ordering;
+use std::convert::Infallible;
+use std::fmt::{ self, Debug };
+use std::collections::{ HashSet, VecDeque };
+use std::hash::{ Hash, Hasher };
+use std::iter::FromIterator;
+use std::ops::{ Bound, RangeBounds };
+use std::rc::Rc;
+
+mod arena;
+pub use arena::CritBitArena;
+
+// The root, and the number of entries under it.
+pub struct CritBit<K,V>( Option<CritBitNode<K,V>>, usize ) where K: CritKey;
+
+pub enum CritBitNode<K,V> where K: CritKey {
+    Leaf ( K, V ),
+    // The branches, the critical bit, and the number of leaves under the node.
+    Internal ( Branches<K,V>, usize, usize ),
+}
+
+pub type Branches<K,V> = ( Option<Box<CritBitNode<K,V>>>, Option<Box<CritBitNode<K,V>>> );
+
+/// A key that can be stored in a `CritBit`, viewed as a string of bits.
+///
+/// Two keys that differ must differ on some bit within the longer of them. A key type with no bits
+/// at all, like `()`, therefore has to compare all its keys equal, and a tree keyed by it holds at
+/// most one entry, which each insert overwrites.
+pub trait CritKey: Eq {
+    /// The position of the first bit where `self` and `other` differ, or `None` if they are equal.
+    fn crit_bit( &self, other: &Self ) -> Option<usize>;
+
+    /// Whether the bit at `pos` is set; positions past the end of the key read as unset.
+    fn bit_set( &self, pos: usize ) -> bool;
+
+    /// The number of bits in the key; every key starting with `self` agrees with it on these.
+    fn bit_len( &self ) -> usize;
+
+    /// Whether `other` starts with all of `self`. Fixed-width keys are only prefixes of themselves.
+    fn is_prefix_of( &self, other: &Self ) -> bool {
+        *self == *other
+    }
+}
+
+#[inline(always)]
+fn bit_at<T: PrimInt>( value: &T, pos: &u32 ) -> bool {
+    value.rotate_left(*pos).leading_zeros() == 0
+}
+
+// Signed keys are read with their sign bit flipped, so that bit order is numeric order and negative
+// keys come before the rest. Flipping the same bit in both keys leaves where they differ unchanged.
+macro_rules! int_crit_key {
+    ( $( $t:ty => $flip:expr ),* ) => { $(
+        impl CritKey for $t {
+            fn crit_bit( &self, other: &$t ) -> Option<usize> {
+                if *self == *other { None } else { Some( ( *self ^ *other ).leading_zeros() as usize ) }
+            }
+
+            fn bit_set( &self, pos: usize ) -> bool {
+                pos < <$t>::BITS as usize && bit_at( &( *self ^ $flip ), &( pos as u32 ) )
+            }
+
+            fn bit_len( &self ) -> usize {
+                <$t>::BITS as usize
+            }
+        }
+    )* }
+}
+
+int_crit_key!( u8 => 0, u16 => 0, u32 => 0, u64 => 0, u128 => 0, usize => 0 );
+int_crit_key!( i8 => i8::MIN, i16 => i16::MIN, i32 => i32::MIN, i64 => i64::MIN, i128 => i128::MIN, isize => isize::MIN );
+
+// The only `()` there is has no bits to tell it apart from anything.
+impl CritKey for () {
+    fn crit_bit( &self, _: &() ) -> Option<usize> {
+        None
+    }
+
+    fn bit_set( &self, _: usize ) -> bool {
+        false
+    }
+
+    fn bit_len( &self ) -> usize {
+        0
+    }
+}
+
+// Byte strings are read as a run of 9-bit symbols, one per byte: a set bit marking that the byte is
+// present, then the byte itself. A key that ends early therefore sorts before any longer key it is a
+// prefix of, and "a" never collides with "a\0".
+fn bytes_crit_bit( a: &[u8], b: &[u8] ) -> Option<usize> {
+    match a.iter().zip( b.iter() ).position( |( x, y )| x != y ) {
+        Some( i ) => Some( i * 9 + 1 + ( a[ i ] ^ b[ i ] ).leading_zeros() as usize ),
+        None if a.len() != b.len() => Some( a.len().min( b.len() ) * 9 ),
+        None => None
+    }
+}
+
+fn bytes_bit_set( bytes: &[u8], pos: usize ) -> bool {
+    match ( bytes.get( pos / 9 ), pos % 9 ) {
+        ( None, _ ) => false,
+        ( Some( _ ), 0 ) => true,
+        ( Some( byte ), bit ) => byte & ( 0x80 >> ( bit - 1 ) ) != 0
+    }
+}
+
+impl CritKey for Vec<u8> {
+    fn crit_bit( &self, other: &Vec<u8> ) -> Option<usize> {
+        bytes_crit_bit( self, other )
+    }
+
+    fn bit_set( &self, pos: usize ) -> bool {
+        bytes_bit_set( self, pos )
+    }
+
+    fn bit_len( &self ) -> usize {
+        self.len() * 9
+    }
+
+    fn is_prefix_of( &self, other: &Self ) -> bool {
+        other.starts_with( self )
+    }
+}
+
+impl<'a> CritKey for &'a [u8] {
+    fn crit_bit( &self, other: &&'a [u8] ) -> Option<usize> {
+        bytes_crit_bit( self, other )
+    }
+
+    fn bit_set( &self, pos: usize ) -> bool {
+        bytes_bit_set( self, pos )
+    }
+
+    fn bit_len( &self ) -> usize {
+        self.len() * 9
+    }
+
+    fn is_prefix_of( &self, other: &Self ) -> bool {
+        other.starts_with( self )
+    }
+}
+
+/// The top `prefix_len()` bits of an integer, like the `10.0.0.0/8` of a route. Each bit is read as a
+/// set bit marking that it is present, then the bit itself, the same way byte strings are; a prefix
+/// therefore sorts before the longer ones it covers, and `is_prefix_of` means "covers".
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Prefix<T> {
+    bits: T,
+    len: usize,
+}
+
+fn int_width<T: PrimInt>() -> usize {
+    T::zero().count_zeros() as usize
+}
+
+impl<T: PrimInt> Prefix<T> {
+    /// Keeps the top `len` bits of `bits` and clears the rest. Panics if `len` is wider than `T`.
+    pub fn new( bits: T, len: usize ) -> Prefix<T> {
+        let width = int_width::<T>();
+        assert!( len <= width, "A {}-bit integer has no /{} prefix", width, len );
+        let mask = if len == 0 { T::zero() } else { !T::zero() << ( width - len ) };
+        Prefix { bits: bits & mask, len }
+    }
+
+    pub fn bits( &self ) -> T {
+        self.bits
+    }
+
+    pub fn prefix_len( &self ) -> usize {
+        self.len
+    }
+}
+
+impl<T: PrimInt> CritKey for Prefix<T> {
+    fn crit_bit( &self, other: &Prefix<T> ) -> Option<usize> {
+        let shared = self.len.min( other.len );
+        let diff = ( self.bits ^ other.bits ).leading_zeros() as usize;
+        if diff < shared {
+            Some( diff * 2 + 1 )
+        } else if self.len != other.len {
+            Some( shared * 2 )
+        } else {
+            None
+        }
+    }
+
+    fn bit_set( &self, pos: usize ) -> bool {
+        match ( pos / 2, pos % 2 ) {
+            ( i, _ ) if i >= self.len => false,
+            ( _, 0 ) => true,
+            ( i, _ ) => bit_at( &self.bits, &( i as u32 ) )
+        }
+    }
+
+    fn bit_len( &self ) -> usize {
+        self.len * 2
+    }
+
+    fn is_prefix_of( &self, other: &Prefix<T> ) -> bool {
+        self.len <= other.len && ( self.bits ^ other.bits ).leading_zeros() as usize >= self.len
+    }
+}
+
+// Spells out a key bit by bit, so that debug checks can name the key involved without every key type
+// having to implement `Debug`.
+#[cfg(debug_assertions)]
+fn key_bits<K: CritKey>( key: &K ) -> String {
+    ( 0..key.bit_len() ).map( |pos| if key.bit_set( pos ) { '1' } else { '0' } ).collect()
+}
+
+// Orders keys the way the tree lays them out: by their first differing bit, most significant first.
+fn bit_order<T: CritKey>( a: &T, b: &T ) -> Ordering {
+    match a.crit_bit( b ) {
+        None => Ordering::Equal,
+        Some( crit ) if b.bit_set( crit ) => Ordering::Less,
+        Some( _ ) => Ordering::Greater
+    }
+}
+
+type Pair<'a, K, V> = ( &'a K, &'a V );
+type Bracket<'a, K, V> = ( Option<Pair<'a, K, V>>, Option<Pair<'a, K, V>>, Option<Pair<'a, K, V>> );
+// An internal node passed on the way down, and whether the right branch was taken.
+type Step<'a, K, V> = ( &'a CritBitNode<K,V>, bool );
+type Seed<'a, K, V> = ( Vec<Step<'a, K, V>>, usize, Option<&'a CritBitNode<K,V>>, Option<Pair<'a, K, V>> );
+
+// Where a missing `key` falls along the path towards it: the number of steps before the first node
+// splitting on a later bit than where `key` and `leaf` differ, the subtree below them (all of which
+// is on one side of `key`), and whether `key` sorts after that subtree. `None` if `leaf` holds `key`.
+fn divergence<'a, K: CritKey, V>( path: &[Step<'a, K, V>], leaf: &'a CritBitNode<K,V>, key: &K ) -> Option<( usize, &'a CritBitNode<K,V>, bool )> {
+    let crit = leaf.first().0.crit_bit( key )?;
+    let above = path.iter().take_while( |&&( node, _ )| ! node.splits_below( crit ) ).count();
+    Some( ( above, path.get( above ).map_or( leaf, |&( node, _ )| node ), key.bit_set( crit ) ) )
+}
+
+// The last entry before the subtree a path leads to: the end of the left branch at the deepest point
+// the path went right.
+fn entry_before<'a, K: CritKey, V>( path: &[Step<'a, K, V>] ) -> Option<Pair<'a, K, V>> {
+    path.iter().rev().find( |&&( _, right )| right ).map( |&( node, _ )| node.branch( false ).last() )
+}
+
+fn entry_after<'a, K: CritKey, V>( path: &[Step<'a, K, V>] ) -> Option<Pair<'a, K, V>> {
+    path.iter().rev().find( |&&( _, right )| ! right ).map( |&( node, _ )| node.branch( true ).first() )
+}
+
+// Where the front of a range starting at `key` begins: the path towards `key`, how many steps of it
+// lead above the start, the subtree the range starts with (if it isn't wholly before `key`), and the
+// entry before the range.
+fn front_seed<'a, K: CritKey, V>( root: &'a CritBitNode<K,V>, key: &K, inclusive: bool ) -> Seed<'a, K, V> {
+    let ( path, leaf ) = root.path_to( key );
+    let ( above, start, before ) = match divergence( &path, leaf, key ) {
+        None if inclusive => ( path.len(), Some( leaf ), entry_before( &path ) ),
+        None => ( path.len(), None, Some( leaf.first() ) ),
+        Some( ( above, subtree, true ) ) => ( above, None, Some( subtree.last() ) ),
+        Some( ( above, subtree, false ) ) => ( above, Some( subtree ), entry_before( &path[ ..above ] ) ),
+    };
+    ( path, above, start, before )
+}
+
+// Seeds the front of an `Entries` at the first key after `key` (or at `key` itself, if `inclusive`),
+// along with the entry before that for the back to stop at.
+fn seek_front<'a, K: CritKey, V>( root: &'a CritBitNode<K,V>, key: &K, inclusive: bool ) -> ( Vec<&'a CritBitNode<K,V>>, Option<&'a K> ) {
+    let ( path, above, start, before ) = front_seed( root, key, inclusive );
+
+    // Right branches skipped on the way down are still to come, deepest first
+    let mut front : Vec<_> = path[ ..above ].iter()
+        .filter( |&&( _, right )| ! right )
+        .map( |&( node, _ )| node.branch( true ) )
+        .collect();
+    front.extend( start );
+    ( front, before.map( |( k, _ )| k ) )
+}
+
+// The mirror image of `seek_front`: seeds the back at the last key before `key` (or at `key`), along
+// with the entry after that for the front to stop at.
+fn seek_back<'a, K: CritKey, V>( root: &'a CritBitNode<K,V>, key: &K, inclusive: bool ) -> ( Vec<&'a CritBitNode<K,V>>, Option<&'a K> ) {
+    let ( path, leaf ) = root.path_to( key );
+    let ( above, start, after ) = match divergence( &path, leaf, key ) {
+        None if inclusive => ( path.len(), Some( leaf ), entry_after( &path ) ),
+        None => ( path.len(), None, Some( leaf.first() ) ),
+        Some( ( above, subtree, true ) ) => ( above, Some( subtree ), entry_after( &path[ ..above ] ) ),
+        Some( ( above, subtree, false ) ) => ( above, None, Some( subtree.first() ) ),
+    };
+
+    let mut back : Vec<_> = path[ ..above ].iter()
+        .filter( |&&( _, right )| right )
+        .map( |&( node, _ )| node.branch( false ) )
+        .collect();
+    back.extend( start );
+    ( back, after.map( |( k, _ )| k ) )
+}
+
+// Reverses the referenced values themselves, not the references.
+fn reverse_values<V>( values: &mut [&mut V] ) {
+    let len = values.len();
+    for i in 0..len / 2 {
+        let ( front, back ) = values.split_at_mut( len - 1 - i );
+        std::mem::swap( &mut *front[ i ], &mut *back[ 0 ] );
+    }
+}
+
+impl<K,V> CritBit<K,V> where K: CritKey {
+    pub fn new() -> CritBit<K,V> {
+        CritBit( None, 0 )
+    }
+
+    // For a root whose size isn't known separately, which it keeps a count of itself.
+    fn from_root( root: Option<CritBitNode<K,V>> ) -> CritBit<K,V> {
+        let len = root.as_ref().map_or( 0, CritBitNode::len );
+        CritBit( root, len )
+    }
+
+    /// Builds a tree in O(n) from pairs already sorted by key with no repeats, joining neighbours
+    /// on the bit where they differ instead of searching for each key's place.
+    pub fn from_sorted( pairs: Vec<( K, V )> ) -> CritBit<K,V> {
+        let len = pairs.len();
+        let mut pairs = pairs.into_iter().peekable();
+        // The right edge of the tree so far, each subtree with the bit it splits from the next on
+        let mut spine : Vec<( CritBitNode<K,V>, usize )> = Vec::new();
+        while let Some( ( k, v ) ) = pairs.next() {
+            let split = pairs.peek().map( |next| {
+                let crit = k.crit_bit( &next.0 );
+                debug_assert!( crit.is_some_and( |c| next.0.bit_set( c ) ), "from_sorted needs strictly increasing keys" );
+                crit.unwrap_or( 0 )
+            } );
+            // Spine subtrees split off on a later bit than the one parting this key from the next
+            // join up with it before anything to its right
+            let mut node = CritBitNode::Leaf ( k, v );
+            while let Some( &( _, crit ) ) = spine.last() {
+                if split.is_some_and( |split| split > crit ) {
+                    break;
+                }
+                let ( left, _ ) = spine.pop().expect( "We just looked at it" );
+                node = CritBitNode::join( left, node, crit );
+            }
+            match split {
+                Some( crit ) => spine.push( ( node, crit ) ),
+                None => return CritBit( Some( node ), len )
+            }
+        }
+        CritBit::new()
+    }
+
+    /// Builds a tree from `pairs`, keeping the last value given for a repeated key.
+    pub fn from_pairs_last( pairs: Vec<( K, V )> ) -> CritBit<K,V> {
+        let mut tree = CritBit::new();
+        for ( k, v ) in pairs {
+            tree.insert( k, v );
+        }
+        tree
+    }
+
+    /// Builds a tree from `pairs`, keeping the first value given for a repeated key.
+    pub fn from_pairs_first( pairs: Vec<( K, V )> ) -> CritBit<K,V> {
+        let mut tree = CritBit::new();
+        for ( k, v ) in pairs {
+            tree.insert_if_absent( k, v );
+        }
+        tree
+    }
+
+    /// Empties the tree, taking it apart one node at a time rather than recursing down it.
+    pub fn clear( &mut self ) {
+        self.1 = 0;
+        let mut stack : Vec<CritBitNode<K,V>> = self.0.take().into_iter().collect();
+        while let Some( node ) = stack.pop() {
+            if let CritBitNode::Internal ( ( left, right ), _, _ ) = node {
+                stack.extend( left.map( |kid| *kid ) );
+                stack.extend( right.map( |kid| *kid ) );
+            }
+        }
+    }
+
+    pub fn len( &self ) -> usize {
+        self.1
+    }
+
+    pub fn is_empty( &self ) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn get( &self, key: &K ) -> Option<&V> {
+        match self.0 {
+            Some( ref node ) => node.get( key ),
+            None => None
+        }
+    }
+
+    pub fn get_mut( &mut self, key: &K ) -> Option<&mut V> {
+        match self.0 {
+            Some( ref mut node ) => node.get_mut( key ).map( |( _, v )| v ),
+            None => None
+        }
+    }
+
+    /// Finds `key`'s place in the tree, for updating its value in place or inserting one. This only
+    /// borrows the tree to look: an occupied entry notes the turns down to its leaf and follows them
+    /// again, without comparing keys, to reach the value; a vacant one notes the bit `key` will split
+    /// off on.
+    pub fn entry( &mut self, key: K ) -> Entry<'_, K, V> {
+        let crit = match self.0 {
+            Some( ref root ) => {
+                let ( path, leaf ) = root.path_to( &key );
+                match leaf.first().0.crit_bit( &key ) {
+                    None => {
+                        let turns = path.iter().map( |&( _, right )| right ).collect();
+                        return Entry::Occupied ( OccupiedEntry { tree: self, turns } );
+                    },
+                    Some( crit ) => crit
+                }
+            },
+            // Any bit will do to place the first key
+            None => 0
+        };
+        Entry::Vacant ( VacantEntry { tree: self, key, crit } )
+    }
+
+    /// Returns the entry whose key is the longest prefix of `key`, such as the most specific route
+    /// covering an address. A stored prefix of `key` can only be the final leaf on the way down, or
+    /// a leaf hanging off to the left where `key` carries on past its end.
+    pub fn longest_prefix_match<'a>( &'a self, key: &K ) -> Option<( &'a K, &'a V )> {
+        let mut best = None;
+        self.covering( |pos| key.bit_set( pos ), |k| k.is_prefix_of( key ), |entry| best = Some( entry ) );
+        best
+    }
+
+    // Descends towards the key read through `bit_set`, passing `found` each entry on the way whose
+    // key `covers` it, shortest first.
+    fn covering<'a, B, C, F>( &'a self, bit_set: B, covers: C, mut found: F )
+        where B: Fn( usize ) -> bool, C: Fn( &K ) -> bool, F: FnMut( Pair<'a, K, V> )
+    {
+        let mut node = match self.0 {
+            Some( ref root ) => root,
+            None => return
+        };
+        loop {
+            match *node {
+                CritBitNode::Leaf ( ref k, ref v ) => {
+                    if covers( k ) {
+                        found( ( k, v ) );
+                    }
+                    return;
+                },
+                CritBitNode::Internal ( _, crit, _ ) => {
+                    let right = bit_set( crit );
+                    if right {
+                        if let CritBitNode::Leaf ( ref k, ref v ) = *node.branch( false ) {
+                            if covers( k ) {
+                                found( ( k, v ) );
+                            }
+                        }
+                    }
+                    node = node.branch( right );
+                }
+            }
+        }
+    }
+
+    /// Returns the key at rank `len / 2`: the exact median for an odd number of entries, and the
+    /// upper of the two middle keys for an even number. Internal nodes count the leaves under them,
+    /// so this steers a single descent by rank.
+    pub fn median_key( &self ) -> Option<&K> {
+        self.0.as_ref().map( |root| root.nth( self.1 / 2 ).0 )
+    }
+
+    /// Replaces the value of the `i`th entry in key order, returning the old value, or `None` if
+    /// there are no more than `i` entries. Like `median_key`, this descends by rank.
+    pub fn set_nth_value( &mut self, i: usize, value: V ) -> Option<V> {
+        match self.0 {
+            Some( ref mut root ) if i < self.1 => Some( std::mem::replace( root.nth_mut( i ), value ) ),
+            _ => None
+        }
+    }
+
+    /// Returns the entry with the smallest key, following left branches all the way down.
+    pub fn min( &self ) -> Option<( &K, &V )> {
+        self.0.as_ref().map( CritBitNode::first )
+    }
+
+    /// Returns the entry with the largest key, following right branches all the way down.
+    pub fn max( &self ) -> Option<( &K, &V )> {
+        self.0.as_ref().map( CritBitNode::last )
+    }
+
+    /// Returns the entry for `key` if there is one, and otherwise the entry for the smallest key
+    /// greater than it (if any), using a single descent.
+    pub fn find_or_successor<'a>( &'a self, key: &K ) -> Result<( &'a K, &'a V ), Option<( &'a K, &'a V )>> {
+        match self.bracket( key ) {
+            ( _, Some( entry ), _ ) => Ok( entry ),
+            ( _, None, after ) => Err( after ),
+        }
+    }
+
+    /// Returns the entry with the largest key less than or equal to `key`.
+    pub fn floor<'a>( &'a self, key: &K ) -> Option<( &'a K, &'a V )> {
+        match self.bracket( key ) {
+            ( _, Some( entry ), _ ) | ( Some( entry ), None, _ ) => Some( entry ),
+            _ => None
+        }
+    }
+
+    /// Returns the entry with the smallest key greater than or equal to `key`.
+    pub fn ceiling<'a>( &'a self, key: &K ) -> Option<( &'a K, &'a V )> {
+        match self.bracket( key ) {
+            ( _, Some( entry ), _ ) | ( _, None, Some( entry ) ) => Some( entry ),
+            _ => None
+        }
+    }
+
+    /// Returns the entry with the smallest key strictly greater than `key`, which needn't be stored.
+    pub fn successor<'a>( &'a self, key: &K ) -> Option<( &'a K, &'a V )> {
+        self.bracket( key ).2
+    }
+
+    /// Returns the entry with the largest key strictly less than `key`, which needn't be stored.
+    pub fn predecessor<'a>( &'a self, key: &K ) -> Option<( &'a K, &'a V )> {
+        self.bracket( key ).0
+    }
+
+    /// Returns the entry with the smallest key in `[lo, hi)`, descending only towards `lo`.
+    pub fn range_first<'a>( &'a self, lo: &K, hi: &K ) -> Option<( &'a K, &'a V )> {
+        let first = match self.bracket( lo ) {
+            ( _, Some( entry ), _ ) | ( _, None, Some( entry ) ) => entry,
+            _ => return None
+        };
+        if bit_order( first.0, hi ) == Ordering::Less { Some( first ) } else { None }
+    }
+
+    /// Returns the entry with the largest key in `[lo, hi)`, descending only towards `hi`.
+    pub fn range_last<'a>( &'a self, lo: &K, hi: &K ) -> Option<( &'a K, &'a V )> {
+        match self.bracket( hi ).0 {
+            Some( last ) if bit_order( last.0, lo ) != Ordering::Less => Some( last ),
+            _ => None
+        }
+    }
+
+    // Finds the entries just before, at, and just after `key` with one walk down the tree.
+    fn bracket<'a>( &'a self, key: &K ) -> Bracket<'a, K, V> {
+        let root = match self.0 {
+            Some( ref root ) => root,
+            None => return ( None, None, None )
+        };
+        let ( path, leaf ) = root.path_to( key );
+        match divergence( &path, leaf, key ) {
+            None => ( entry_before( &path ), Some( leaf.first() ), entry_after( &path ) ),
+            Some( ( above, subtree, true ) ) => ( Some( subtree.last() ), None, entry_after( &path[ ..above ] ) ),
+            Some( ( above, subtree, false ) ) => ( entry_before( &path[ ..above ] ), None, Some( subtree.first() ) ),
+        }
+    }
+
+    pub fn contains_key( &self, key: &K ) -> bool {
+        self.get( key ).is_some()
+    }
+
+    pub fn insert( &mut self, key: K, value: V ) -> Option<V> {
+        self.insert_observed( key, value, &mut |_| () ).1
+    }
+
+    /// Inserts `value` only if `key` isn't present yet, returning whether it did. Otherwise the
+    /// existing value is kept and `value` is dropped.
+    pub fn insert_if_absent( &mut self, key: K, value: V ) -> bool {
+        if self.contains_key( &key ) {
+            return false;
+        }
+        self.insert( key, value );
+        true
+    }
+
+    /// Inserts like `insert`, but spells out whether `key` was new or had its value replaced.
+    pub fn upsert( &mut self, key: K, value: V ) -> Upsert<V> {
+        match self.insert( key, value ) {
+            Some( old ) => Upsert::Replaced ( old ),
+            None => Upsert::Inserted
+        }
+    }
+
+    /// Inserts like `insert`, but also hands back a reference to the value now stored for `key`.
+    pub fn insert_and_get( &mut self, key: K, value: V ) -> ( &mut V, Option<V> ) {
+        self.insert_observed( key, value, &mut |_| () )
+    }
+
+    /// Inserts like `insert`, but also returns the critical bits of the internal nodes passed on
+    /// the way down, followed by the critical bit of the new split if the key was not present.
+    pub fn insert_traced( &mut self, key: K, value: V ) -> ( Option<V>, Vec<usize> ) {
+        let mut path = Vec::new();
+        let old = self.insert_observed( key, value, &mut |crit| path.push( crit ) ).1;
+        ( old, path )
+    }
+
+    /// Inserts like `insert`, overwriting any value already there, and returns the entry for `key`
+    /// to carry on working with it.
+    pub fn insert_entry( &mut self, key: K, value: V ) -> OccupiedEntry<'_, K, V> {
+        match self.entry( key ) {
+            Entry::Occupied ( mut entry ) => { entry.insert( value ); entry },
+            Entry::Vacant ( entry ) => entry.insert_entry( value )
+        }
+    }
+
+    /// Inserts like `insert`, and also returns the number of entries afterwards, for tracking the
+    /// tree's growth as it happens.
+    pub fn insert_returning_len( &mut self, key: K, value: V ) -> ( Option<V>, usize ) {
+        let old = self.insert( key, value );
+        ( old, self.1 )
+    }
+
+    /// Returns the value for `key`, inserting `default()` first if there isn't one. `default` is
+    /// only called when `key` is absent.
+    pub fn get_or_insert_with<F: FnOnce() -> V>( &mut self, key: K, default: F ) -> &mut V {
+        self.entry( key ).or_insert_with( default )
+    }
+
+    // Inserts a key known to be absent, that first differs from its nearest stored key at `crit`.
+    fn insert_vacant( &mut self, key: K, value: V, crit: usize ) -> &mut V {
+        self.1 += 1;
+        match self.0 {
+            Some( ref mut node ) => node.insert( key, value, Some( crit ), &mut |_| () ).0,
+            None => self.0.insert( CritBitNode::Leaf( key, value ) ).value_mut()
+        }
+    }
+
+    fn insert_observed<F: FnMut( usize )>( &mut self, key: K, value: V, observe: &mut F ) -> ( &mut V, Option<V> ) {
+        match self.0 {
+            Some( ref mut node ) => {
+                let nearest = node.nearest( &key );
+                let crit = nearest.crit_bit( &key );
+                debug_assert!( crit.is_none_or( |c| c < key.bit_len().max( nearest.bit_len() ) ),
+                               "Keys that differ must differ within their bits; zero-width keys must all be equal" );
+                if crit.is_some() {
+                    self.1 += 1;
+                }
+                node.insert( key, value, crit, observe )
+            },
+            None => {
+                self.1 = 1;
+                ( self.0.insert( CritBitNode::Leaf( key, value ) ).value_mut(), None )
+            }
+        }
+    }
+
+    /// Joins two trees under a single new root in O(1).
+    ///
+    /// The keys of the two trees must fall on opposite sides of a single bit that lies above every
+    /// split in either tree, which is what `try_merge_disjoint` checks. This is only checked in
+    /// debug builds; breaking it cannot cause memory unsafety, but silently leaves lookups and
+    /// iteration order wrong.
+    pub fn merge_disjoint_unchecked( mut self, mut other: Self ) -> Self {
+        debug_assert!( self.separable( &other ), "merge_disjoint_unchecked on trees that interleave" );
+        match ( self.0.take(), other.0.take() ) {
+            ( Some( mine ), Some( theirs ) ) => {
+                let crit = mine.first().0.crit_bit( theirs.first().0 ).unwrap_or( 0 );
+                let ( lo, hi ) = if mine.first().0.bit_set( crit ) { ( theirs, mine ) } else { ( mine, theirs ) };
+                CritBit( Some( CritBitNode::join( lo, hi, crit ) ), self.1 + other.1 )
+            },
+            ( mine, None ) => CritBit( mine, self.1 ),
+            ( None, theirs ) => CritBit( theirs, other.1 ),
+        }
+    }
+
+    /// Joins two trees in O(1) if their keys fall on opposite sides of a bit above every split in
+    /// either tree, and hands both back untouched otherwise.
+    pub fn try_merge_disjoint( self, other: Self ) -> Result<Self, ( Self, Self )> {
+        if self.separable( &other ) {
+            Ok( self.merge_disjoint_unchecked( other ) )
+        } else {
+            Err( ( self, other ) )
+        }
+    }
+
+    // Whether the first bit on which the two trees' keys differ lies above every split in either.
+    fn separable( &self, other: &Self ) -> bool {
+        match ( &self.0, &other.0 ) {
+            ( Some( mine ), Some( theirs ) ) => {
+                mine.first().0.crit_bit( theirs.first().0 )
+                    .is_some_and( |crit| mine.splits_below( crit ) && theirs.splits_below( crit ) )
+            },
+            _ => true
+        }
+    }
+
+    /// Moves every entry of `other` into `self`, leaving `other` empty. Where both have a key, the
+    /// value from `other` wins. Trees split apart by a single bit are joined in O(1); otherwise
+    /// `other`'s entries are inserted one by one.
+    pub fn append( &mut self, other: &mut CritBit<K,V> ) {
+        let mine = std::mem::take( self );
+        *self = match mine.try_merge_disjoint( std::mem::take( other ) ) {
+            Ok( merged ) => merged,
+            Err( ( mut mine, theirs ) ) => {
+                mine.extend( theirs.into_sorted_vec() );
+                mine
+            }
+        };
+    }
+
+    /// Replaces the value for `key` with `new` only if it currently equals `expected`, returning
+    /// the value it replaced. On a mismatch `new` is handed back in `Err` together with the current
+    /// value, or `None` if `key` is absent.
+    pub fn compare_and_swap( &mut self, key: &K, expected: &V, new: V ) -> Result<V, ( V, Option<&V> )> where V: PartialEq {
+        match self.get_mut( key ) {
+            Some( current ) if *current == *expected => Ok( std::mem::replace( current, new ) ),
+            current => Err( ( new, current.map( |v| &*v ) ) )
+        }
+    }
+
+    pub fn remove( &mut self, key: &K ) -> Option<V> {
+        let whole = match self.0 {
+            Some( CritBitNode::Internal ( .. ) ) => false,
+            Some( CritBitNode::Leaf ( ref k, _ ) ) if *k == *key => true,
+            _ => return None
+        };
+        let removed = if whole {
+            match self.0.take() {
+                Some( CritBitNode::Leaf ( _, v ) ) => Some( v ),
+                _ => unreachable!("We just checked that this was a leaf...")
+            }
+        } else {
+            self.0.as_mut().and_then( |node| node.remove( key ) )
+        };
+        if removed.is_some() {
+            self.1 -= 1;
+        }
+        #[cfg(debug_assertions)]
+        self.assert_path_well_formed( |crit| key.bit_set( crit ), || format!( "remove( {} )", key_bits( key ) ) );
+        removed
+    }
+
+    // Removes the leaf that `turns` lead to, collapsing its parent into the other branch.
+    fn remove_turns( &mut self, turns: &[bool] ) -> ( K, V ) {
+        let leaf = match turns.split_last() {
+            None => self.0.take().expect( "An occupied entry means a non-empty tree" ),
+            Some( ( &right, above ) ) => {
+                let mut node = self.0.as_mut().expect( "An occupied entry means a non-empty tree" );
+                for &turn in above {
+                    if let CritBitNode::Internal ( _, _, ref mut len ) = *node {
+                        *len -= 1;
+                    }
+                    node = node.branch_mut( turn );
+                }
+                node.collapse( ! right )
+            }
+        };
+        self.1 -= 1;
+        match leaf {
+            CritBitNode::Leaf ( k, v ) => {
+                #[cfg(debug_assertions)]
+                self.assert_path_well_formed( |crit| k.bit_set( crit ), || format!( "remove_entry( {} )", key_bits( &k ) ) );
+                ( k, v )
+            },
+            CritBitNode::Internal ( .. ) => unreachable!("Only leaves hold values")
+        }
+    }
+
+    /// Checks the structural invariants: every internal node has both branches, critical bits
+    /// strictly increase going down, and each node's keys agree on every bit above its critical
+    /// bit and are split by it. The cached length must also match the number of leaves.
+    pub fn is_well_formed( &self ) -> bool {
+        self.0.as_ref().map_or( self.1 == 0, |node| node.check() && node.len() == self.1 )
+    }
+
+    // Checks the nodes along the path a removal rebuilt, which `right` steers down by critical bit,
+    // so that the check costs no more than the removal itself.
+    #[cfg(debug_assertions)]
+    fn assert_path_well_formed<R: Fn( usize ) -> bool, F: FnOnce() -> String>( &self, right: R, operation: F ) {
+        let formed = self.0.as_ref().map_or( self.1 == 0, |root| root.len() == self.1 && root.check_path( right ) );
+        if ! formed {
+            panic!( "{} left the tree malformed", operation() );
+        }
+    }
+
+    /// Removes `key` like `remove`, and also returns the number of entries left.
+    pub fn remove_returning_len( &mut self, key: &K ) -> ( Option<V>, usize ) {
+        let removed = self.remove( key );
+        ( removed, self.1 )
+    }
+
+    /// Removes `key` like `remove`, and also returns the smallest remaining key after it, for
+    /// loops that delete the current entry and move on to the next.
+    pub fn remove_returning_successor( &mut self, key: &K ) -> ( Option<V>, Option<K> ) where K: Clone {
+        let successor = self.bracket( key ).2.map( |( k, _ )| k.clone() );
+        ( self.remove( key ), successor )
+    }
+
+    /// Removes `key` like `remove`, but pushes its value onto `pool` instead of dropping it, so an
+    /// expensive allocation can be reused for a later insert. Returns whether `key` was present.
+    pub fn remove_into_pool( &mut self, key: &K, pool: &mut Vec<V> ) -> bool {
+        match self.remove( key ) {
+            Some( v ) => { pool.push( v ); true },
+            None => false
+        }
+    }
+
+    /// Removes and returns up to `n` of the smallest entries, smallest first, in one walk down the
+    /// left edge of the tree.
+    pub fn remove_min_n( &mut self, n: usize ) -> Vec<( K, V )> {
+        self.remove_extremes( n, false )
+    }
+
+    /// Removes and returns up to `n` of the largest entries, largest first.
+    pub fn remove_max_n( &mut self, n: usize ) -> Vec<( K, V )> {
+        self.remove_extremes( n, true )
+    }
+
+    /// Removes and returns the smallest entry, walking only the left edge of the tree.
+    pub fn pop_first( &mut self ) -> Option<( K, V )> {
+        self.remove_extremes( 1, false ).pop()
+    }
+
+    /// Removes and returns the largest entry, walking only the right edge of the tree.
+    pub fn pop_last( &mut self ) -> Option<( K, V )> {
+        self.remove_extremes( 1, true ).pop()
+    }
+
+    /// Merges several trees into one with a single ordered pass over all of them. When a key is in
+    /// more than one tree, `combine` folds its values together in the order the trees were given.
+    /// The merged entries come out in key order, so the result is built once with `from_sorted`.
+    pub fn merge_many<F: FnMut( &K, V, V ) -> V>( trees: Vec<CritBit<K,V>>, mut combine: F ) -> CritBit<K,V> {
+        let mut merged = Vec::with_capacity( trees.iter().map( CritBit::len ).sum() );
+        let mut inputs : Vec<_> = trees.into_iter()
+            .map( |mut tree| tree.remove_min_n( usize::MAX ).into_iter().peekable() )
+            .collect();
+        loop {
+            let first = inputs.iter_mut().enumerate()
+                .filter_map( |( i, input )| input.peek().map( |e| ( i, &e.0 ) ) )
+                .min_by( |a, b| bit_order( a.1, b.1 ) )
+                .map( |( i, _ )| i );
+            let ( key, mut value ) = match first.and_then( |i| inputs[ i ].next() ) {
+                Some( entry ) => entry,
+                None => return CritBit::from_sorted( merged )
+            };
+            for input in inputs[ first.unwrap_or( 0 ) + 1.. ].iter_mut() {
+                if let Some( ( _, v ) ) = input.next_if( |e| e.0 == key ) {
+                    value = combine( &key, value, v );
+                }
+            }
+            merged.push( ( key, value ) );
+        }
+    }
+
+    /// Moves every entry out into a vector in key order, allocated up front at exactly the right
+    /// size.
+    pub fn into_sorted_vec( mut self ) -> Vec<( K, V )> {
+        let mut out = Vec::with_capacity( self.len() );
+        let mut stack : Vec<CritBitNode<K,V>> = self.0.take().into_iter().collect();
+        while let Some( node ) = stack.pop() {
+            match node {
+                CritBitNode::Leaf ( k, v ) => out.push( ( k, v ) ),
+                CritBitNode::Internal ( ( left, right ), _, _ ) => {
+                    stack.extend( right.map( |kid| *kid ) );
+                    stack.extend( left.map( |kid| *kid ) );
+                }
+            }
+        }
+        out
+    }
+
+    /// Breaks the tree into at most `n` trees covering consecutive key ranges, with equal numbers
+    /// of entries except for a smaller last one. An `n` of zero is treated as one. Each shard comes
+    /// off the low end already sorted, so it is built in one pass with `from_sorted`.
+    pub fn split_shards( mut self, n: usize ) -> Vec<CritBit<K,V>> {
+        let per_shard = self.len().div_ceil( n.max( 1 ) );
+        let mut shards = Vec::new();
+        while ! self.is_empty() {
+            shards.push( CritBit::from_sorted( self.remove_min_n( per_shard ) ) );
+        }
+        shards
+    }
+
+    /// Moves every entry with a key greater than or equal to `key` out into a new tree, leaving the
+    /// smaller ones in `self`. Only the nodes along the path to `key` are rebuilt.
+    pub fn split_off( &mut self, key: &K ) -> CritBit<K,V> {
+        let ( low, high ) = match self.0.take() {
+            Some( root ) => root.split( key, false ),
+            None => ( None, None )
+        };
+        self.0 = low;
+        let high = CritBit::from_root( high );
+        self.1 -= high.1;
+        high
+    }
+
+    /// Moves every entry with a key less than or equal to `key` out into a new tree, leaving the
+    /// larger ones in `self`.
+    pub fn split_off_le( &mut self, key: &K ) -> CritBit<K,V> {
+        let ( low, high ) = match self.0.take() {
+            Some( root ) => root.split( key, true ),
+            None => ( None, None )
+        };
+        self.0 = high;
+        let low = CritBit::from_root( low );
+        self.1 -= low.1;
+        low
+    }
+
+    fn remove_extremes( &mut self, n: usize, high: bool ) -> Vec<( K, V )> {
+        let mut out = Vec::new();
+        if n > 0 {
+            if let Some( root ) = self.0.take() {
+                self.0 = root.take_extremes( n, high, &mut out );
+            }
+        }
+        self.1 -= out.len();
+        #[cfg(debug_assertions)]
+        self.assert_path_well_formed( |_| high, || format!( "{}( {} )", if high { "remove_max_n" } else { "remove_min_n" }, n ) );
+        out
+    }
+
+    /// Keeps only the entries for which `f` returns true, collapsing the nodes left with one branch.
+    pub fn retain<F: FnMut( &K, &V ) -> bool>( &mut self, mut f: F ) {
+        self.try_retain( |k, v| Ok::<bool, Infallible>( f( k, v ) ) ).unwrap_or_else( |never| match never {} )
+    }
+
+    /// Removes every key that a shorter stored key is a prefix of, as when aggregating routes, and
+    /// returns how many went. A prefix sorts just before the keys it covers, so one pass in key
+    /// order finds them all; keys that are whole values, like integers, cover only themselves.
+    pub fn aggregate_prefixes( &mut self ) -> usize {
+        let mut cover : Option<&K> = None;
+        let keep : Vec<bool> = self.keys().map( |k| {
+            let covered = cover.is_some_and( |c| c.is_prefix_of( k ) );
+            if ! covered {
+                cover = Some( k );
+            }
+            ! covered
+        } ).collect();
+        let before = self.1;
+        let mut keep = keep.into_iter();
+        self.retain( |_, _| keep.next().unwrap_or( true ) );
+        before - self.1
+    }
+
+    /// Keeps only the entries for which `f` returns `Ok( true )`. The first error stops the pass
+    /// and is returned; entries already visited stay removed.
+    pub fn try_retain<E, F: FnMut( &K, &mut V ) -> Result<bool, E>>( &mut self, mut f: F ) -> Result<(), E> {
+        let ( root, removed, result ) = match self.0.take() {
+            Some( root ) => root.try_retain( &mut f ),
+            None => ( None, 0, Ok( () ) )
+        };
+        self.0 = root;
+        self.1 -= removed;
+        result
+    }
+
+    /// Cyclically shifts the values `n` places towards smaller keys (or larger ones, for negative
+    /// `n`), leaving the keys where they are. `n` wraps around modulo `len()`.
+    pub fn rotate_values( &mut self, n: isize ) {
+        let mut values : Vec<_> = self.values_mut().collect();
+        if values.is_empty() {
+            return;
+        }
+        let shift = n.rem_euclid( values.len() as isize ) as usize;
+        reverse_values( &mut values[ ..shift ] );
+        reverse_values( &mut values[ shift.. ] );
+        reverse_values( &mut values );
+    }
+
+    pub fn iter( &self ) -> Entries<'_, K, V> {
+        Entries::new( self.0.iter().collect() )
+    }
+
+    /// Iterates in key order over just the entries whose keys start with `prefix`.
+    pub fn iter_prefix( &self, prefix: &K ) -> Entries<'_, K, V> {
+        Entries::new( self.prefix_subtree( prefix ).into_iter().collect() )
+    }
+
+    pub fn contains_prefix( &self, prefix: &K ) -> bool {
+        self.prefix_subtree( prefix ).is_some()
+    }
+
+    /// Counts the keys starting with `prefix` by reading off the size of the subtree they share.
+    pub fn count_prefix( &self, prefix: &K ) -> usize {
+        self.prefix_subtree( prefix ).map_or( 0, CritBitNode::len )
+    }
+
+    /// Iterates in key order over the entries whose keys agree with `prefix` on its first
+    /// `prefix_len` bits, such as every address in a subnet. `prefix_len` is capped at
+    /// `prefix.bit_len()`, and zero matches everything.
+    pub fn iter_prefix_bits( &self, prefix: &K, prefix_len: usize ) -> Entries<'_, K, V> {
+        Entries::new( self.prefix_bits_subtree( prefix, prefix_len.min( prefix.bit_len() ) ).into_iter().collect() )
+    }
+
+    // The subtree holding exactly the keys that start with `prefix`, if there are any.
+    fn prefix_subtree( &self, prefix: &K ) -> Option<&CritBitNode<K,V>> {
+        self.prefix_bits_subtree( prefix, prefix.bit_len() )
+    }
+
+    // The subtree holding exactly the keys that agree with `prefix` on its first `bits` bits.
+    fn prefix_bits_subtree( &self, prefix: &K, bits: usize ) -> Option<&CritBitNode<K,V>> {
+        self.subtree_under( bits, |pos| prefix.bit_set( pos ), |k| prefix.crit_bit( k ).is_none_or( |crit| crit >= bits ) )
+    }
+
+    // Steers by `bit_set` until the next split is at or past `bits`, then keeps the subtree there
+    // if its keys pass `matches`.
+    fn subtree_under<B, M>( &self, bits: usize, bit_set: B, matches: M ) -> Option<&CritBitNode<K,V>>
+        where B: Fn( usize ) -> bool, M: Fn( &K ) -> bool
+    {
+        let mut node = self.0.as_ref()?;
+        while let CritBitNode::Internal ( _, crit, _ ) = *node {
+            if crit >= bits {
+                break;
+            }
+            node = node.branch( bit_set( crit ) );
+        }
+        // Every key below agrees with this one up to the subtree's first split, which is past
+        // `bits`, so either they all match the prefix that far or none do.
+        if matches( node.first().0 ) { Some( node ) } else { None }
+    }
+
+    /// Iterates in key order like `iter`, but with mutable access to the values. Nodes are never
+    /// moved while the iterator lives, so every `&mut V` it hands out stays valid alongside the
+    /// ones after it.
+    pub fn iter_mut( &mut self ) -> EntriesMut<'_, K, V> {
+        EntriesMut::new( self.0.iter_mut().collect() )
+    }
+
+    pub fn keys( &self ) -> Keys<'_, K, V> {
+        Keys { entries: self.iter() }
+    }
+
+    pub fn values( &self ) -> Values<'_, K, V> {
+        Values { entries: self.iter() }
+    }
+
+    pub fn values_mut( &mut self ) -> ValuesMut<'_, K, V> {
+        ValuesMut { entries: self.iter_mut() }
+    }
+
+    /// Walks the keys of both trees together in order, reporting whether each is in one or both.
+    pub fn merge_keys<'a>( &'a self, other: &'a CritBit<K,V> ) -> MergeKeys<'a, K, V> {
+        MergeKeys { left: self.iter().peekable(), right: other.iter().peekable() }
+    }
+
+    /// Returns up to `before` entries preceding `key`, the entry for `key` itself if present, and up
+    /// to `after` entries following it, in key order. Both sides start from where `key` falls, so
+    /// this costs O(depth + before + after).
+    pub fn neighbors<'a>( &'a self, key: &K, before: usize, after: usize ) -> Vec<( &'a K, &'a V )> {
+        let mut result : Vec<_> = self.bounded( Bound::Unbounded, Bound::Excluded( key ) ).rev().take( before ).collect();
+        result.reverse();
+        let mut following = self.bounded( Bound::Included( key ), Bound::Unbounded ).peekable();
+        result.extend( following.next_if( |&( k, _ )| *k == *key ) );
+        result.extend( following.take( after ) );
+        result
+    }
+
+    /// Returns up to `limit` entries with keys greater than `after` (or from the start, for `None`),
+    /// in key order. The second half is the cursor to pass as `after` for the next page, or `None`
+    /// once the last page has been returned.
+    pub fn page<'a>( &'a self, after: Option<&K>, limit: usize ) -> ( Vec<( &'a K, &'a V )>, Option<&'a K> ) {
+        let mut entries = match after {
+            Some( key ) => self.iter_from( key, false ),
+            None => self.iter()
+        }.peekable();
+        let page : Vec<_> = entries.by_ref().take( limit ).collect();
+        let cursor = match ( page.last(), entries.peek() ) {
+            ( Some( &( k, _ ) ), Some( _ ) ) => Some( k ),
+            _ => None
+        };
+        ( page, cursor )
+    }
+
+    // Iterates over the entries from `key` onwards, skipping `key` itself unless `inclusive`.
+    fn iter_from( &self, key: &K, inclusive: bool ) -> Entries<'_, K, V> {
+        match self.0 {
+            Some( ref root ) => {
+                let ( front, front_last ) = seek_front( root, key, inclusive );
+                Entries { front, back: vec![ root ], front_last, back_last: None }
+            },
+            None => self.iter()
+        }
+    }
+
+    /// Iterates in key order over the entries with keys in `range`, e.g. `t.range( lo..hi )` or
+    /// `t.range( ..=hi )`. Only the paths towards the bounds are walked to find where to start and
+    /// stop; an empty or backwards range yields nothing.
+    pub fn range<R: RangeBounds<K>>( &self, range: R ) -> Entries<'_, K, V> {
+        self.bounded( range.start_bound(), range.end_bound() )
+    }
+
+    /// Like `range`, but with mutable access to the values.
+    pub fn range_mut<R: RangeBounds<K>>( &mut self, range: R ) -> RangeMut<'_, K, V> {
+        let left = self.bounded( range.start_bound(), range.end_bound() ).count();
+        if left == 0 {
+            return RangeMut { entries: EntriesMut::new( VecDeque::new() ), left };
+        }
+        // Note the way down to the start while only borrowing, then take the same turns mutably
+        let ( turns, start ) = match ( range.start_bound(), self.0.as_ref() ) {
+            ( Bound::Included( lo ), Some( root ) ) | ( Bound::Excluded( lo ), Some( root ) ) => {
+                let ( path, above, start, _ ) = front_seed( root, lo, matches!( range.start_bound(), Bound::Included( _ ) ) );
+                ( path[ ..above ].iter().map( |&( _, right )| right ).collect(), start.is_some() )
+            },
+            _ => ( Vec::new(), true )
+        };
+        let mut pending = VecDeque::new();
+        let mut node = self.0.as_mut().expect( "A non-empty range means a non-empty tree" );
+        for right in turns {
+            node = match *node {
+                CritBitNode::Internal ( ( Some( ref mut left ), Some( ref mut kid ) ), _, _ ) =>
+                    if right { kid } else { pending.push_front( &mut **kid ); left },
+                _ => unreachable!("Internal nodes should always have both branches filled, what happened?")
+            };
+        }
+        if start {
+            pending.push_front( node );
+        }
+        RangeMut { entries: EntriesMut::new( pending ), left }
+    }
+
+    fn bounded( &self, start: Bound<&K>, end: Bound<&K> ) -> Entries<'_, K, V> {
+        let empty = match ( start, end ) {
+            ( Bound::Included( lo ), Bound::Included( hi ) ) => bit_order( lo, hi ) == Ordering::Greater,
+            ( Bound::Included( lo ), Bound::Excluded( hi ) )
+                | ( Bound::Excluded( lo ), Bound::Included( hi ) )
+                | ( Bound::Excluded( lo ), Bound::Excluded( hi ) ) => bit_order( lo, hi ) != Ordering::Less,
+            _ => false
+        };
+        let root = match self.0 {
+            Some( ref root ) if ! empty => root,
+            _ => return Entries::new( Vec::new() )
+        };
+        let ( front, front_last ) = match start {
+            Bound::Included( lo ) => seek_front( root, lo, true ),
+            Bound::Excluded( lo ) => seek_front( root, lo, false ),
+            Bound::Unbounded => ( vec![ root ], None )
+        };
+        let ( back, back_last ) = match end {
+            Bound::Included( hi ) => seek_back( root, hi, true ),
+            Bound::Excluded( hi ) => seek_back( root, hi, false ),
+            Bound::Unbounded => ( vec![ root ], None )
+        };
+        Entries { front, back, front_last, back_last }
+    }
+
+    /// Like `iter`, but also yields how many internal nodes sit above each leaf.
+    pub fn iter_with_depth( &self ) -> DepthEntries<'_, K, V> {
+        DepthEntries { stack: self.0.iter().map( |node| ( node, 0 ) ).collect() }
+    }
+
+    /// Counts the nodes on the longest path from the root down to a leaf, both ends included.
+    pub fn depth( &self ) -> usize {
+        self.iter_with_depth().map( |( _, _, depth )| depth + 1 ).max().unwrap_or( 0 )
+    }
+
+    /// Averages the number of nodes on each leaf's path from the root, counted as for `depth`.
+    /// An empty tree reports zero.
+    pub fn avg_depth( &self ) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let total : usize = self.iter_with_depth().map( |( _, _, depth )| depth + 1 ).sum();
+        total as f64 / self.len() as f64
+    }
+
+    /// Walks the internal nodes top-down, yielding each one's critical bit along with the number
+    /// of entries under its left and right branches. The counts are read off the sizes every node
+    /// keeps, so a full walk is O(n).
+    pub fn iter_internal( &self ) -> Internals<'_, K, V> {
+        Internals { stack: self.0.iter().collect() }
+    }
+
+    /// Returns the critical bit of the deepest internal node on the search paths of both `a` and
+    /// `b`: the node where they part, or the last one before the leaf they share. `None` if the
+    /// tree has no internal nodes.
+    pub fn common_ancestor_bit( &self, a: &K, b: &K ) -> Option<usize> {
+        let mut common = None;
+        let mut node = self.0.as_ref()?;
+        while let CritBitNode::Internal ( _, crit, _ ) = *node {
+            common = Some( crit );
+            if a.bit_set( crit ) != b.bit_set( crit ) {
+                break;
+            }
+            node = node.branch( a.bit_set( crit ) );
+        }
+        common
+    }
+
+    /// Returns the values ordered by value rather than by key. They aren't indexed by value, so
+    /// this collects and sorts them: O(n log n).
+    pub fn values_sorted( &self ) -> Vec<&V> where V: Ord {
+        let mut values : Vec<_> = self.iter().map( |( _, v )| v ).collect();
+        values.sort();
+        values
+    }
+
+    /// Sums `size_of` over every value, for estimating the memory held by variable-size values.
+    pub fn total_value_bytes<F: Fn( &V ) -> usize>( &self, size_of: F ) -> usize {
+        self.iter().map( |( _, v )| size_of( v ) ).sum()
+    }
+
+    /// Counts the entries for which `f` holds, in one traversal and without allocating.
+    pub fn count_matching<F: FnMut( &K, &V ) -> bool>( &self, mut f: F ) -> usize {
+        self.0.as_ref().map_or( 0, |node| node.count_matching( &mut f ) )
+    }
+
+    /// Returns every key mapped to `value`, in key order. This scans the whole tree, so it is O(n).
+    pub fn keys_for_value<'a>( &'a self, value: &V ) -> Vec<&'a K> where V: PartialEq {
+        self.iter().filter( |&( _, v )| *v == *value ).map( |( k, _ )| k ).collect()
+    }
+
+    /// Folds the tree bottom-up: each leaf is summarized by `leaf`, and the summaries of the two
+    /// halves of every internal node are merged with `combine`. Returns `None` for an empty tree.
+    pub fn reduce<S, Leaf, Combine>( &self, leaf: Leaf, combine: Combine ) -> Option<S>
+        where Leaf: Fn( &K, &V ) -> S, Combine: Fn( S, S ) -> S
+    {
+        self.0.as_ref().map( |node| node.reduce( &leaf, &combine ) )
+    }
+
+    /// Moves every value behind an `Rc`, sharing a single allocation between all values that
+    /// compare equal. Since this changes the value type, it consumes the tree and returns a
+    /// `CritBit<K, Rc<V>>`; the shared values are reachable as `&V` through `Rc`'s `Deref`, but
+    /// can no longer be mutated in place while shared.
+    pub fn intern_values( mut self ) -> CritBit<K, Rc<V>> where V: Hash + Eq {
+        let mut pool = HashSet::new();
+        CritBit( self.0.take().map( |node| node.intern( &mut pool ) ), self.1 )
+    }
+}
+
+impl<T,V> CritBit<Prefix<T>,V> where T: PrimInt {
+    /// Finds the most specific stored prefix covering `addr`, like a routing table lookup, and
+    /// returns it with its length in bits.
+    pub fn find_longest_prefix( &self, addr: T ) -> Option<( &Prefix<T>, usize, &V )> {
+        self.longest_prefix_match( &Prefix::new( addr, int_width::<T>() ) ).map( |( k, v )| ( k, k.len, v ) )
+    }
+}
+
+// Methods that rely on keys being fixed-width integers.
+impl<K,V> CritBit<K,V> where K: CritKey + PrimInt {
+    /// Looks up all of `keys` in a single walk of the tree, sending each key only down the branch
+    /// it belongs to. Results come back in the order the keys were given, but `keys` itself is
+    /// left sorted.
+    pub fn bulk_get<'a>( &'a self, keys: &mut [K] ) -> Vec<Option<&'a V>> {
+        let mut order : Vec<usize> = ( 0..keys.len() ).collect();
+        order.sort_by_key( |&i| keys[ i ] );
+        let sorted : Vec<K> = order.iter().map( |&i| keys[ i ] ).collect();
+        keys.copy_from_slice( &sorted );
+
+        let mut found = vec![ None; keys.len() ];
+        if let Some( ref node ) = self.0 {
+            let mut pending : Vec<usize> = ( 0..keys.len() ).collect();
+            node.bulk_get( keys, &mut pending, &mut found );
+        }
+
+        let mut results = vec![ None; keys.len() ];
+        for ( sorted_pos, &original_pos ) in order.iter().enumerate() {
+            results[ original_pos ] = found[ sorted_pos ];
+        }
+        results
+    }
+
+    /// Counts, for every bit position of `K`, how many internal nodes split on it.
+    pub fn crit_bit_histogram( &self ) -> Vec<usize> {
+        let mut counts = vec![ 0; K::zero().count_zeros() as usize ];
+        if let Some( ref node ) = self.0 {
+            node.crit_bit_histogram( &mut counts );
+        }
+        counts
+    }
+
+    /// Counts the leading bits that are the same in every stored key, and so are never split on.
+    /// A tree with fewer than two keys reports the full width of `K`.
+    pub fn compact_key_width( &self ) -> usize {
+        match self.0 {
+            Some( CritBitNode::Internal ( _, crit, _ ) ) => crit,
+            _ => K::zero().count_zeros() as usize
+        }
+    }
+}
+
+impl<V> CritBit<Vec<u8>,V> {
+    /// Builds a table for `longest_prefix_match` from `( bytes, bits, value )` triples, keying each
+    /// value by the first `bits` bits of `bytes`; a later repeat of a prefix replaces the earlier
+    /// one. Byte-string keys end on whole bytes, so this panics unless `bits` is a multiple of
+    /// eight within `bytes`; use `Prefix` keys for other lengths.
+    pub fn from_ranges( ranges: Vec<( Vec<u8>, usize, V )> ) -> CritBit<Vec<u8>,V> {
+        ranges.into_iter().map( |( mut bytes, bits, v )| {
+            assert!( bits % 8 == 0 && bits / 8 <= bytes.len(), "A {}-byte key has no /{} prefix made of whole bytes", bytes.len(), bits );
+            bytes.truncate( bits / 8 );
+            ( bytes, v )
+        } ).collect()
+    }
+}
+
+// Methods that rely on keys being byte strings, so that a prefix can be any slice.
+impl<K,V> CritBit<K,V> where K: CritKey + AsRef<[u8]> {
+    /// Clears `out` and fills it with the entries whose keys start with `prefix`, in key order.
+    /// Reusing one buffer across many queries saves allocating a fresh result for each.
+    pub fn collect_prefix_into<'a>( &'a self, prefix: &[u8], out: &mut Vec<( &'a K, &'a V )> ) {
+        out.clear();
+        out.extend( Entries::new( self.byte_prefix_subtree( prefix ).into_iter().collect() ) );
+    }
+
+    /// Returns the longest run of leading bytes that every key shares: the whole key when there's
+    /// only one, and nothing for an empty tree. All keys agree up to the root's critical bit, so
+    /// the bytes before it are read off any of them.
+    pub fn common_prefix( &self ) -> Vec<u8> {
+        let root = match self.0 {
+            Some( ref root ) => root,
+            None => return Vec::new()
+        };
+        let key = root.first().0.as_ref();
+        match *root {
+            CritBitNode::Internal ( _, crit, _ ) => key[ ..crit / 9 ].to_vec(),
+            CritBitNode::Leaf ( .. ) => key.to_vec()
+        }
+    }
+
+    /// Counts the keys under each distinct first `byte_len` bytes, with keys shorter than that
+    /// counted under themselves. Every subtree that splits past those bytes is one group whose
+    /// size it already keeps, so only the nodes above them are visited.
+    pub fn rollup_counts( &self, byte_len: usize ) -> CritBit<Vec<u8>,usize> {
+        let bits = byte_len * 9;
+        let group = |k: &K| k.as_ref()[ ..byte_len.min( k.as_ref().len() ) ].to_vec();
+        let mut groups = Vec::new();
+        let mut stack : Vec<&CritBitNode<K,V>> = self.0.iter().collect();
+        while let Some( node ) = stack.pop() {
+            match *node {
+                CritBitNode::Internal ( _, crit, len ) if crit >= bits => groups.push( ( group( node.first().0 ), len ) ),
+                CritBitNode::Internal ( ( Some( ref left ), Some( ref right ) ), _, _ ) => {
+                    stack.push( right );
+                    stack.push( left );
+                },
+                CritBitNode::Leaf ( ref k, _ ) => groups.push( ( group( k ), 1 ) ),
+                _ => unreachable!("Internal nodes should always have both branches filled, what happened?")
+            }
+        }
+        // Groups come out in key order, and truncating keeps them there
+        CritBit::from_sorted( groups )
+    }
+
+    /// Iterates in key order like `iter`, but with each key viewed as its bytes.
+    pub fn iter_bytes( &self ) -> impl DoubleEndedIterator<Item = ( &[u8], &V )> {
+        self.iter().map( |( k, v )| ( k.as_ref(), v ) )
+    }
+
+    /// Keeps only the entries whose keys, viewed as bytes, pass `f`; otherwise just like `retain`.
+    pub fn retain_keys<F: FnMut( &[u8] ) -> bool>( &mut self, mut f: F ) {
+        self.retain( |k, _| f( k.as_ref() ) )
+    }
+
+    /// Returns every entry whose key is a prefix of `key`, shortest first, such as all the routes
+    /// covering an address. Like `longest_prefix_match`, this takes a single descent.
+    pub fn prefix_matches<'a>( &'a self, key: &[u8] ) -> Vec<( &'a K, &'a V )> {
+        let mut matches = Vec::new();
+        self.covering( |pos| bytes_bit_set( key, pos ), |k| key.starts_with( k.as_ref() ), |entry| matches.push( entry ) );
+        matches
+    }
+
+    /// Cuts the tree down to just the entries whose keys start with `prefix`, leaving it empty if
+    /// there are none. Only the path towards the prefix is walked, so this is O(prefix bits) plus
+    /// the cost of dropping what is cut away.
+    pub fn keep_only_prefix( &mut self, prefix: &[u8] ) {
+        let bits = prefix.len() * 9;
+        let mut node = match self.0.take() {
+            Some( root ) => root,
+            None => return
+        };
+        self.1 = 0;
+        while let CritBitNode::Internal ( _, crit, _ ) = node {
+            if crit >= bits {
+                break;
+            }
+            node = match node {
+                CritBitNode::Internal ( ( Some( left ), Some( right ) ), _, _ ) => {
+                    let ( kept, cut ) = if bytes_bit_set( prefix, crit ) { ( right, left ) } else { ( left, right ) };
+                    // Dropping through a tree takes apart even a deep subtree without recursing
+                    drop( CritBit::from_root( Some( *cut ) ) );
+                    *kept
+                },
+                _ => unreachable!("Internal nodes should always have both branches filled, what happened?")
+            };
+        }
+        if node.first().0.as_ref().starts_with( prefix ) {
+            self.1 = node.len();
+            self.0 = Some( node );
+        } else {
+            drop( CritBit::from_root( Some( node ) ) );
+        }
+    }
+
+    /// Returns the entry with the smallest key starting with `prefix`, descending to the prefix's
+    /// subtree and then down its left edge.
+    pub fn prefix_min( &self, prefix: &[u8] ) -> Option<( &K, &V )> {
+        self.byte_prefix_subtree( prefix ).map( CritBitNode::first )
+    }
+
+    /// Returns the entry with the largest key starting with `prefix`, down the subtree's right edge.
+    pub fn prefix_max( &self, prefix: &[u8] ) -> Option<( &K, &V )> {
+        self.byte_prefix_subtree( prefix ).map( CritBitNode::last )
+    }
+
+    // The subtree holding exactly the keys that start with `prefix`, if there are any.
+    fn byte_prefix_subtree( &self, prefix: &[u8] ) -> Option<&CritBitNode<K,V>> {
+        self.subtree_under( prefix.len() * 9, |pos| bytes_bit_set( prefix, pos ), |k| k.as_ref().starts_with( prefix ) )
+    }
+}
+
+impl<K: CritKey + Clone, V: Clone> Clone for CritBit<K, V> {
+    fn clone( &self ) -> CritBit<K,V> {
+        CritBit( self.0.clone(), self.1 )
+    }
+}
+
+// Works through an explicit stack rather than recursing, so that even a degenerate tree as deep as
+// its keys are long can't overflow the call stack.
+impl<K: CritKey + Clone, V: Clone> Clone for CritBitNode<K, V> {
+    fn clone( &self ) -> CritBitNode<K,V> {
+        enum Work<'a, K: CritKey + 'a, V: 'a> {
+            Visit ( &'a CritBitNode<K,V> ),
+            Join ( usize, usize ),
+        }
+        let mut work = vec![ Work::Visit ( self ) ];
+        let mut done = Vec::new();
+        while let Some( item ) = work.pop() {
+            match item {
+                Work::Visit ( node ) => match *node {
+                    CritBitNode::Leaf ( ref k, ref v ) => done.push( CritBitNode::Leaf ( k.clone(), v.clone() ) ),
+                    CritBitNode::Internal ( ( ref left, ref right ), crit, len ) => {
+                        work.push( Work::Join ( crit, len ) );
+                        work.extend( right.as_deref().map( Work::Visit ) );
+                        work.extend( left.as_deref().map( Work::Visit ) );
+                    }
+                },
+                Work::Join ( crit, len ) => {
+                    let right = done.pop().map( Box::new );
+                    let left = done.pop().map( Box::new );
+                    done.push( CritBitNode::Internal ( ( left, right ), crit, len ) );
+                }
+            }
+        }
+        done.pop().expect( "Cloning always leaves exactly one node behind" )
+    }
+}
+
+// One line per node, children indented under their parent with the left branch first.
+impl<K: CritKey + Debug, V: Debug> Debug for CritBit<K, V> {
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result {
+        let mut stack = match self.0 {
+            Some( ref root ) => vec![ ( root, 0 ) ],
+            None => return write!( f, "Empty" )
+        };
+        let mut first = true;
+        while let Some( ( node, depth ) ) = stack.pop() {
+            if ! first {
+                writeln!( f )?;
+            }
+            first = false;
+            write!( f, "{:1$}", "", depth * 2 )?;
+            match *node {
+                CritBitNode::Leaf ( ref k, ref v ) => write!( f, "Leaf( {:?} => {:?} )", k, v )?,
+                CritBitNode::Internal ( ( ref left, ref right ), crit, _ ) => {
+                    write!( f, "Internal( bit {} )", crit )?;
+                    stack.extend( right.as_deref().map( |kid| ( kid, depth + 1 ) ) );
+                    stack.extend( left.as_deref().map( |kid| ( kid, depth + 1 ) ) );
+                }
+            }
+        }
+        Ok( () )
+    }
+}
+
+// Equality and hashing go by the entries in order, not by the tree's shape.
+impl<K: CritKey, V: PartialEq> PartialEq for CritBit<K, V> {
+    fn eq( &self, other: &CritBit<K,V> ) -> bool {
+        self.iter().eq( other.iter() )
+    }
+}
+
+impl<K: CritKey, V: Eq> Eq for CritBit<K, V> {}
+
+impl<K: CritKey + Hash, V: Hash> Hash for CritBit<K, V> {
+    fn hash<H: Hasher>( &self, state: &mut H ) {
+        let mut count = 0;
+        for entry in self.iter() {
+            entry.hash( state );
+            count += 1;
+        }
+        state.write_usize( count );
+    }
+}
+
+impl<K: CritKey, V> FromIterator<( K, V )> for CritBit<K, V> {
+    fn from_iter<I: IntoIterator<Item = ( K, V )>>( pairs: I ) -> CritBit<K,V> {
+        let mut tree = CritBit::new();
+        tree.extend( pairs );
+        tree
+    }
+}
+
+// Like `insert`, later pairs win over earlier ones with the same key.
+impl<K: CritKey, V> Extend<( K, V )> for CritBit<K, V> {
+    fn extend<I: IntoIterator<Item = ( K, V )>>( &mut self, pairs: I ) {
+        for ( k, v ) in pairs {
+            self.insert( k, v );
+        }
+    }
+}
+
+// Consumes the tree, yielding its entries in key order.
+impl<K: CritKey, V> IntoIterator for CritBit<K, V> {
+    type Item = ( K, V );
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter( mut self ) -> IntoIter<K, V> {
+        IntoIter { stack: self.0.take().into_iter().collect() }
+    }
+}
+
+impl<'a, K: CritKey, V> IntoIterator for &'a CritBit<K, V> {
+    type Item = ( &'a K, &'a V );
+    type IntoIter = Entries<'a, K, V>;
+
+    fn into_iter( self ) -> Entries<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K: CritKey, V> IntoIterator for &'a mut CritBit<K, V> {
+    type Item = ( &'a K, &'a mut V );
+    type IntoIter = EntriesMut<'a, K, V>;
+
+    fn into_iter( self ) -> EntriesMut<'a, K, V> {
+        self.iter_mut()
+    }
+}
+
+impl<K: CritKey, V> Drop for CritBit<K, V> {
+    fn drop( &mut self ) {
+        self.clear();
+    }
+}
+
+impl<K: CritKey, V> Default for CritBit<K, V> {
+    fn default() -> CritBit<K,V> {
+        CritBit::new()
+    }
+}
+
+// Walks in from both ends with separate stacks, and stops once either end reaches a key the other has
+// already yielded (or one that is out of bounds, when seeded with a `*_last` key).
+pub struct Entries<'a, K, V> where K: CritKey {
+    front: Vec<&'a CritBitNode<K,V>>,
+    back: Vec<&'a CritBitNode<K,V>>,
+    front_last: Option<&'a K>,
+    back_last: Option<&'a K>,
+}
+
+impl<'a, K: CritKey, V> Entries<'a, K, V> {
+    fn new( roots: Vec<&'a CritBitNode<K,V>> ) -> Entries<'a, K, V> {
+        Entries { back: roots.clone(), front: roots, front_last: None, back_last: None }
+    }
+
+    fn step( &mut self, from_back: bool ) -> Option<( &'a K, &'a V )> {
+        let ( stack, other_last ) = if from_back { ( &mut self.back, self.front_last ) } else { ( &mut self.front, self.back_last ) };
+        while let Some( node ) = stack.pop() {
+            match *node {
+                CritBitNode::Leaf ( ref k, ref v ) => {
+                    if other_last.is_some_and( |last| std::ptr::eq( last, k ) ) {
+                        break;
+                    }
+                    if from_back { self.back_last = Some( k ) } else { self.front_last = Some( k ) }
+                    return Some( ( k, v ) );
+                },
+                CritBitNode::Internal ( ( ref left, ref right ), _, _ ) => {
+                    let ( first, second ) = if from_back { ( left, right ) } else { ( right, left ) };
+                    stack.extend( first.as_deref() );
+                    stack.extend( second.as_deref() );
+                }
+            }
+        }
+        self.front.clear();
+        self.back.clear();
+        None
+    }
+}
+
+impl<'a, K: CritKey, V> Iterator for Entries<'a, K, V> {
+    type Item = ( &'a K, &'a V );
+
+    fn next( &mut self ) -> Option<( &'a K, &'a V )> {
+        self.step( false )
+    }
+}
+
+impl<'a, K: CritKey, V> DoubleEndedIterator for Entries<'a, K, V> {
+    fn next_back( &mut self ) -> Option<( &'a K, &'a V )> {
+        self.step( true )
+    }
+}
+
+pub struct IntoIter<K, V> where K: CritKey {
+    stack: Vec<CritBitNode<K,V>>,
+}
+
+impl<K: CritKey, V> Iterator for IntoIter<K, V> {
+    type Item = ( K, V );
+
+    fn next( &mut self ) -> Option<( K, V )> {
+        while let Some( node ) = self.stack.pop() {
+            match node {
+                CritBitNode::Leaf ( k, v ) => return Some( ( k, v ) ),
+                CritBitNode::Internal ( ( left, right ), _, _ ) => {
+                    self.stack.extend( right.map( |kid| *kid ) );
+                    self.stack.extend( left.map( |kid| *kid ) );
+                }
+            }
+        }
+        None
+    }
+}
+
+// Whatever wasn't iterated over is taken apart node by node, like `CritBit::clear`.
+impl<K: CritKey, V> Drop for IntoIter<K, V> {
+    fn drop( &mut self ) {
+        for _ in self.by_ref() {}
+    }
+}
+
+pub struct Keys<'a, K, V> where K: CritKey {
+    entries: Entries<'a, K, V>,
+}
+
+impl<'a, K: CritKey, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next( &mut self ) -> Option<&'a K> {
+        self.entries.next().map( |( k, _ )| k )
+    }
+}
+
+impl<'a, K: CritKey, V> DoubleEndedIterator for Keys<'a, K, V> {
+    fn next_back( &mut self ) -> Option<&'a K> {
+        self.entries.next_back().map( |( k, _ )| k )
+    }
+}
+
+pub struct Values<'a, K, V> where K: CritKey {
+    entries: Entries<'a, K, V>,
+}
+
+impl<'a, K: CritKey, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next( &mut self ) -> Option<&'a V> {
+        self.entries.next().map( |( _, v )| v )
+    }
+}
+
+impl<'a, K: CritKey, V> DoubleEndedIterator for Values<'a, K, V> {
+    fn next_back( &mut self ) -> Option<&'a V> {
+        self.entries.next_back().map( |( _, v )| v )
+    }
+}
+
+pub struct ValuesMut<'a, K, V> where K: CritKey {
+    entries: EntriesMut<'a, K, V>,
+}
+
+impl<'a, K: CritKey, V> Iterator for ValuesMut<'a, K, V> {
+    type Item = &'a mut V;
+
+    fn next( &mut self ) -> Option<&'a mut V> {
+        self.entries.next().map( |( _, v )| v )
+    }
+}
+
+impl<'a, K: CritKey, V> DoubleEndedIterator for ValuesMut<'a, K, V> {
+    fn next_back( &mut self ) -> Option<&'a mut V> {
+        self.entries.next_back().map( |( _, v )| v )
+    }
+}
+
+pub enum Entry<'a, K, V> where K: CritKey + 'a, V: 'a {
+    Occupied ( OccupiedEntry<'a, K, V> ),
+    Vacant ( VacantEntry<'a, K, V> ),
+}
+
+pub struct OccupiedEntry<'a, K, V> where K: CritKey + 'a, V: 'a {
+    tree: &'a mut CritBit<K,V>,
+    // The way down to the entry's leaf, `true` for each right branch.
+    turns: Vec<bool>,
+}
+
+pub struct VacantEntry<'a, K, V> where K: CritKey + 'a, V: 'a {
+    tree: &'a mut CritBit<K,V>,
+    key: K,
+    crit: usize,
+}
+
+impl<'a, K: CritKey, V> Entry<'a, K, V> {
+    pub fn key( &self ) -> &K {
+        match *self {
+            Entry::Occupied ( ref entry ) => entry.key(),
+            Entry::Vacant ( ref entry ) => entry.key()
+        }
+    }
+
+    /// Returns the value for the key, inserting `default` first if there isn't one.
+    pub fn or_insert( self, default: V ) -> &'a mut V {
+        self.or_insert_with( || default )
+    }
+
+    /// Returns the value for the key, inserting the result of `f` first if there isn't one.
+    pub fn or_insert_with<F: FnOnce() -> V>( self, f: F ) -> &'a mut V {
+        match self {
+            Entry::Occupied ( entry ) => entry.into_mut(),
+            Entry::Vacant ( entry ) => entry.insert( f() )
+        }
+    }
+
+    /// Returns the value for the key, inserting the result of `f` first if there isn't one. If `f`
+    /// fails, its error is returned and the tree is left as it was.
+    pub fn or_try_insert_with<E, F: FnOnce() -> Result<V, E>>( self, f: F ) -> Result<&'a mut V, E> {
+        match self {
+            Entry::Occupied ( entry ) => Ok( entry.into_mut() ),
+            Entry::Vacant ( entry ) => entry.or_try_insert_with( f )
+        }
+    }
+}
+
+impl<'a, K: CritKey, V> OccupiedEntry<'a, K, V> {
+    pub fn key( &self ) -> &K {
+        self.leaf().first().0
+    }
+
+    pub fn get( &self ) -> &V {
+        self.leaf().first().1
+    }
+
+    pub fn get_mut( &mut self ) -> &mut V {
+        self.tree.0.as_mut().expect( "An occupied entry means a non-empty tree" ).follow_mut( &self.turns ).value_mut()
+    }
+
+    pub fn into_mut( self ) -> &'a mut V {
+        self.tree.0.as_mut().expect( "An occupied entry means a non-empty tree" ).follow_mut( &self.turns ).value_mut()
+    }
+
+    /// Replaces the value, returning the old one.
+    pub fn insert( &mut self, value: V ) -> V {
+        std::mem::replace( self.get_mut(), value )
+    }
+
+    /// Removes the entry, returning its key and value.
+    pub fn remove_entry( self ) -> ( K, V ) {
+        self.tree.remove_turns( &self.turns )
+    }
+
+    pub fn remove( self ) -> V {
+        self.remove_entry().1
+    }
+
+    /// Removes the entry and returns its value if `pred` holds for it, and otherwise leaves it be.
+    /// The leaf is reached through the turns already noted, so there is no second lookup by key.
+    pub fn remove_if<F: FnOnce( &V ) -> bool>( self, pred: F ) -> Option<V> {
+        if pred( self.get() ) { Some( self.remove() ) } else { None }
+    }
+
+    fn leaf( &self ) -> &CritBitNode<K,V> {
+        self.tree.0.as_ref().expect( "An occupied entry means a non-empty tree" ).follow( &self.turns )
+    }
+}
+
+impl<'a, K: CritKey, V> VacantEntry<'a, K, V> {
+    pub fn key( &self ) -> &K {
+        &self.key
+    }
+
+    pub fn insert( self, value: V ) -> &'a mut V {
+        self.tree.insert_vacant( self.key, value, self.crit )
+    }
+
+    /// Inserts `value` and returns the entry now holding it.
+    pub fn insert_entry( self, value: V ) -> OccupiedEntry<'a, K, V> {
+        // The new leaf is reached the way `key` leads down to where it splits off, then one turn more
+        let mut turns = Vec::new();
+        if let Some( ref root ) = self.tree.0 {
+            let mut node = root;
+            while let CritBitNode::Internal ( _, pos, _ ) = *node {
+                if pos >= self.crit {
+                    break;
+                }
+                turns.push( self.key.bit_set( pos ) );
+                node = node.branch( self.key.bit_set( pos ) );
+            }
+            turns.push( self.key.bit_set( self.crit ) );
+        }
+        self.tree.insert_vacant( self.key, value, self.crit );
+        OccupiedEntry { tree: self.tree, turns }
+    }
+
+    /// Inserts the result of `f`, or hands back its error without inserting anything.
+    pub fn or_try_insert_with<E, F: FnOnce() -> Result<V, E>>( self, f: F ) -> Result<&'a mut V, E> {
+        Ok( self.insert( f()? ) )
+    }
+}
+
+// Holds the subtrees not yet visited in key order; each end is expanded independently, so no node is
+// ever reachable from both.
+pub struct EntriesMut<'a, K, V> where K: CritKey {
+    pending: VecDeque<&'a mut CritBitNode<K,V>>,
+    // The addresses of the leaves handed out so far, as a check against ever handing one out twice.
+    #[cfg(debug_assertions)]
+    yielded: HashSet<usize>,
+}
+
+// Notes a leaf handed out by a mutable iterator, panicking if it was handed out before: the two
+// `&mut V` would alias.
+#[cfg(debug_assertions)]
+fn assert_no_aliasing( yielded: &mut HashSet<usize>, leaf: usize ) {
+    assert!( yielded.insert( leaf ), "A mutable iterator handed out the same entry twice" );
+}
+
+impl<'a, K: CritKey, V> EntriesMut<'a, K, V> {
+    fn new( pending: VecDeque<&'a mut CritBitNode<K,V>> ) -> EntriesMut<'a, K, V> {
+        EntriesMut {
+            pending,
+            #[cfg(debug_assertions)]
+            yielded: HashSet::new(),
+        }
+    }
+}
+
+impl<'a, K: CritKey, V> Iterator for EntriesMut<'a, K, V> {
+    type Item = ( &'a K, &'a mut V );
+
+    fn next( &mut self ) -> Option<( &'a K, &'a mut V )> {
+        while let Some( node ) = self.pending.pop_front() {
+            #[cfg(debug_assertions)]
+            let leaf = &*node as *const CritBitNode<K,V> as usize;
+            match *node {
+                CritBitNode::Leaf ( ref k, ref mut v ) => {
+                    #[cfg(debug_assertions)]
+                    assert_no_aliasing( &mut self.yielded, leaf );
+                    return Some( ( k, v ) );
+                },
+                CritBitNode::Internal ( ( Some( ref mut left ), Some( ref mut right ) ), _, _ ) => {
+                    self.pending.push_front( right );
+                    self.pending.push_front( left );
+                },
+                _ => unreachable!("Internal nodes should always have both branches filled, what happened?")
+            }
+        }
+        None
+    }
+}
+
+impl<'a, K: CritKey, V> DoubleEndedIterator for EntriesMut<'a, K, V> {
+    fn next_back( &mut self ) -> Option<( &'a K, &'a mut V )> {
+        while let Some( node ) = self.pending.pop_back() {
+            #[cfg(debug_assertions)]
+            let leaf = &*node as *const CritBitNode<K,V> as usize;
+            match *node {
+                CritBitNode::Leaf ( ref k, ref mut v ) => {
+                    #[cfg(debug_assertions)]
+                    assert_no_aliasing( &mut self.yielded, leaf );
+                    return Some( ( k, v ) );
+                },
+                CritBitNode::Internal ( ( Some( ref mut left ), Some( ref mut right ) ), _, _ ) => {
+                    self.pending.push_back( left );
+                    self.pending.push_back( right );
+                },
+                _ => unreachable!("Internal nodes should always have both branches filled, what happened?")
+            }
+        }
+        None
+    }
+}
+
+// Starts part way into the tree, and stops after the number of entries the range was counted to hold.
+pub struct RangeMut<'a, K, V> where K: CritKey {
+    entries: EntriesMut<'a, K, V>,
+    left: usize,
+}
+
+impl<'a, K: CritKey, V> Iterator for RangeMut<'a, K, V> {
+    type Item = ( &'a K, &'a mut V );
+
+    fn next( &mut self ) -> Option<( &'a K, &'a mut V )> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        self.entries.next()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Upsert<V> {
+    Inserted,
+    Replaced ( V ),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MergeKey<'a, K: 'a> {
+    Both ( &'a K ),
+    Left ( &'a K ),
+    Right ( &'a K ),
+}
+
+pub struct MergeKeys<'a, K, V> where K: CritKey {
+    left: std::iter::Peekable<Entries<'a, K, V>>,
+    right: std::iter::Peekable<Entries<'a, K, V>>,
+}
+
+impl<'a, K: CritKey, V> Iterator for MergeKeys<'a, K, V> {
+    type Item = MergeKey<'a, K>;
+
+    fn next( &mut self ) -> Option<MergeKey<'a, K>> {
+        let order = match ( self.left.peek(), self.right.peek() ) {
+            ( Some( &( l, _ ) ), Some( &( r, _ ) ) ) => bit_order( l, r ),
+            ( Some( _ ), None ) => Ordering::Less,
+            ( None, Some( _ ) ) => Ordering::Greater,
+            ( None, None ) => return None
+        };
+        match order {
+            Ordering::Less => self.left.next().map( |( k, _ )| MergeKey::Left ( k ) ),
+            Ordering::Greater => self.right.next().map( |( k, _ )| MergeKey::Right ( k ) ),
+            Ordering::Equal => {
+                self.right.next();
+                self.left.next().map( |( k, _ )| MergeKey::Both ( k ) )
+            }
+        }
+    }
+}
+
+pub struct DepthEntries<'a, K, V> where K: CritKey {
+    stack: Vec<( &'a CritBitNode<K,V>, usize )>,
+}
+
+impl<'a, K: CritKey, V> Iterator for DepthEntries<'a, K, V> {
+    type Item = ( &'a K, &'a V, usize );
+
+    fn next( &mut self ) -> Option<( &'a K, &'a V, usize )> {
+        while let Some( ( node, depth ) ) = self.stack.pop() {
+            match *node {
+                CritBitNode::Leaf ( ref k, ref v ) => return Some( ( k, v, depth ) ),
+                CritBitNode::Internal ( ( ref left, ref right ), _, _ ) => {
+                    self.stack.extend( right.as_deref().map( |kid| ( kid, depth + 1 ) ) );
+                    self.stack.extend( left.as_deref().map( |kid| ( kid, depth + 1 ) ) );
+                }
+            }
+        }
+        None
+    }
+}
+
+pub struct Internals<'a, K, V> where K: CritKey {
+    stack: Vec<&'a CritBitNode<K,V>>,
+}
+
+impl<'a, K: CritKey, V> Iterator for Internals<'a, K, V> {
+    type Item = ( usize, usize, usize );
+
+    fn next( &mut self ) -> Option<( usize, usize, usize )> {
+        while let Some( node ) = self.stack.pop() {
+            if let CritBitNode::Internal ( ( Some( ref left ), Some( ref right ) ), crit, _ ) = *node {
+                self.stack.push( right );
+                self.stack.push( left );
+                return Some( ( crit, left.len(), right.len() ) );
+            }
+        }
+        None
+    }
+}
+
+impl<K: CritKey, V> CritBitNode<K, V> {
+    // Joins two subtrees that are split by `crit`, `left` holding the keys with it unset.
+    fn join( left: CritBitNode<K,V>, right: CritBitNode<K,V>, crit: usize ) -> CritBitNode<K,V> {
+        let len = left.len() + right.len();
+        CritBitNode::Internal ( ( Some( Box::new( left ) ), Some( Box::new( right ) ) ), crit, len )
+    }
+
+    fn len( &self ) -> usize {
+        match *self {
+            CritBitNode::Leaf ( .. ) => 1,
+            CritBitNode::Internal ( _, _, len ) => len
+        }
+    }
+
+    fn intern( self, pool: &mut HashSet<Rc<V>> ) -> CritBitNode<K, Rc<V>> where V: Hash + Eq {
+        match self {
+            CritBitNode::Leaf ( k, v ) => {
+                let shared = match pool.get( &v ) {
+                    Some( shared ) => shared.clone(),
+                    None => {
+                        let shared = Rc::new( v );
+                        pool.insert( shared.clone() );
+                        shared
+                    }
+                };
+                CritBitNode::Leaf ( k, shared )
+            },
+            CritBitNode::Internal ( ( left, right ), crit, len ) => CritBitNode::Internal ( (
+                left.map( |kid| Box::new( kid.intern( pool ) ) ),
+                right.map( |kid| Box::new( kid.intern( pool ) ) ),
+            ), crit, len ),
+        }
+    }
+
+    fn reduce<S, Leaf, Combine>( &self, leaf: &Leaf, combine: &Combine ) -> S
+        where Leaf: Fn( &K, &V ) -> S, Combine: Fn( S, S ) -> S
+    {
+        match *self {
+            CritBitNode::Leaf ( ref k, ref v ) => leaf( k, v ),
+            CritBitNode::Internal ( ( Some( ref left ), Some( ref right ) ), _, _ ) =>
+                combine( left.reduce( leaf, combine ), right.reduce( leaf, combine ) ),
+            _ => unreachable!("Internal nodes should always have both branches filled, what happened?")
+        }
+    }
+
+    fn count_matching<F: FnMut( &K, &V ) -> bool>( &self, f: &mut F ) -> usize {
+        match *self {
+            CritBitNode::Leaf ( ref k, ref v ) => f( k, v ) as usize,
+            CritBitNode::Internal ( ( ref left, ref right ), _, _ ) =>
+                left.iter().chain( right.iter() ).map( |kid| kid.count_matching( f ) ).sum()
+        }
+    }
+
+    fn crit_bit_histogram( &self, counts: &mut [usize] ) {
+        if let CritBitNode::Internal ( ( ref left, ref right ), crit, _ ) = *self {
+            counts[ crit ] += 1;
+            for kid in left.iter().chain( right.iter() ) {
+                kid.crit_bit_histogram( counts );
+            }
+        }
+    }
+
+    fn get( &self, key: &K ) -> Option<&V> {
+        let mut node = self;
+        loop {
+            match *node {
+                CritBitNode::Leaf ( ref k, ref v ) => return if *k == *key { Some( v ) } else { None },
+                CritBitNode::Internal ( ( ref left, ref right ), crit, _ ) => node = match *if key.bit_set( crit ) { right } else { left } {
+                    Some( ref kid ) => kid,
+                    None => return None
+                }
+            }
+        }
+    }
+
+    // `pending` holds indices into `keys` for the lookups routed to this node.
+    fn bulk_get<'a>( &'a self, keys: &[K], pending: &mut [usize], found: &mut [Option<&'a V>] ) {
+        match *self {
+            CritBitNode::Leaf ( ref k, ref v ) => {
+                for &i in pending.iter() {
+                    if keys[ i ] == *k {
+                        found[ i ] = Some( v );
+                    }
+                }
+            },
+            CritBitNode::Internal ( ( Some( ref left ), Some( ref right ) ), ref crit, _ ) => {
+                let mut split = 0;
+                for j in 0..pending.len() {
+                    if ! keys[ pending[ j ] ].bit_set( *crit ) {
+                        pending.swap( split, j );
+                        split += 1;
+                    }
+                }
+                let ( to_left, to_right ) = pending.split_at_mut( split );
+                if ! to_left.is_empty() {
+                    left.bulk_get( keys, to_left, found );
+                }
+                if ! to_right.is_empty() {
+                    right.bulk_get( keys, to_right, found );
+                }
+            },
+            _ => unreachable!("Internal nodes should always have both branches filled, what happened?")
+        }
+    }
+
+    // Takes the subtree apart and rebuilds it from what `f` keeps, working through an explicit stack
+    // so that neither the walk nor dropping what's removed recurses. Once `f` fails, the subtrees not
+    // yet visited are put back untouched. Returns what is left, and how many entries were removed.
+    fn try_retain<E, F: FnMut( &K, &mut V ) -> Result<bool, E>>( self, f: &mut F ) -> ( Option<CritBitNode<K,V>>, usize, Result<(), E> ) {
+        enum Work<K: CritKey, V> {
+            Visit ( CritBitNode<K,V> ),
+            Join ( usize ),
+        }
+        let mut work = vec![ Work::Visit ( self ) ];
+        let mut done = Vec::new();
+        let mut removed = 0;
+        let mut result = Ok( () );
+        while let Some( item ) = work.pop() {
+            match item {
+                Work::Visit ( node ) if result.is_err() => done.push( Some( node ) ),
+                Work::Visit ( CritBitNode::Leaf ( k, mut v ) ) => match f( &k, &mut v ) {
+                    Ok( true ) => done.push( Some( CritBitNode::Leaf ( k, v ) ) ),
+                    Ok( false ) => { removed += 1; done.push( None ) },
+                    Err( e ) => { result = Err( e ); done.push( Some( CritBitNode::Leaf ( k, v ) ) ) }
+                },
+                Work::Visit ( CritBitNode::Internal ( ( Some( left ), Some( right ) ), crit, _ ) ) => {
+                    work.push( Work::Join ( crit ) );
+                    work.push( Work::Visit ( *right ) );
+                    work.push( Work::Visit ( *left ) );
+                },
+                Work::Visit ( CritBitNode::Internal ( .. ) ) =>
+                    unreachable!("Internal nodes should always have both branches filled, what happened?"),
+                Work::Join ( crit ) => {
+                    let right = done.pop().expect( "Both branches were visited" );
+                    let left = done.pop().expect( "Both branches were visited" );
+                    done.push( match ( left, right ) {
+                        ( Some( left ), Some( right ) ) =>
+                            Some( CritBitNode::join( left, right, crit ) ),
+                        ( kept, None ) | ( None, kept ) => kept
+                    } );
+                }
+            }
+        }
+        ( done.pop().expect( "Retaining always leaves exactly one result behind" ), removed, result )
+    }
+
+    // Removes `key` from somewhere below this internal node, collapsing the node that held its leaf
+    // into the other branch. The counts on the way down are only touched once `key` is known to be
+    // there.
+    fn remove( &mut self, key: &K ) -> Option<V> {
+        self.get( key )?;
+        let mut node = self;
+        loop {
+            let right = match *node {
+                CritBitNode::Internal ( _, crit, ref mut len ) => { *len -= 1; key.bit_set( crit ) },
+                CritBitNode::Leaf ( .. ) => unreachable!("Leaves are removed by their parent")
+            };
+            if let CritBitNode::Leaf ( .. ) = *node.branch( right ) {
+                return match node.collapse( ! right ) {
+                    CritBitNode::Leaf ( _, v ) => Some( v ),
+                    CritBitNode::Internal ( .. ) => unreachable!("We just checked that this was a leaf...")
+                };
+            }
+            node = node.branch_mut( right );
+        }
+    }
+
+    // Moves entries from the low end (or the high end) of this subtree into `out` until it holds
+    // `limit` of them, and returns whatever is left of the subtree. The far branches passed on the
+    // way along the edge are stacked up; once a near branch is used up, the far one next to it takes
+    // its parent's place.
+    fn take_extremes( self, limit: usize, high: bool, out: &mut Vec<( K, V )> ) -> Option<CritBitNode<K,V>> {
+        let mut passed : Vec<( CritBitNode<K,V>, usize )> = Vec::new();
+        let mut next = Some( self );
+        while out.len() < limit {
+            let node = match next.take().or_else( || passed.pop().map( |( far, _ )| far ) ) {
+                Some( node ) => node,
+                None => break
+            };
+            match node {
+                CritBitNode::Leaf ( k, v ) => out.push( ( k, v ) ),
+                CritBitNode::Internal ( ( Some( left ), Some( right ) ), crit, _ ) => {
+                    let ( near, far ) = if high { ( right, left ) } else { ( left, right ) };
+                    passed.push( ( *far, crit ) );
+                    next = Some( *near );
+                },
+                CritBitNode::Internal ( .. ) => unreachable!("Internal nodes should always have both branches filled, what happened?")
+            }
+        }
+        let mut rest = next;
+        while let Some( ( far, crit ) ) = passed.pop() {
+            rest = Some( match rest {
+                Some( near ) if high => CritBitNode::join( far, near, crit ),
+                Some( near ) => CritBitNode::join( near, far, crit ),
+                None => far
+            } );
+        }
+        rest
+    }
+
+    // Replaces an internal node with one of its branches, returning the other.
+    fn collapse( &mut self, keep_right: bool ) -> CritBitNode<K,V> {
+        let ( kept, dropped ) = match *self {
+            CritBitNode::Internal ( ( ref mut left, ref mut right ), _, _ ) =>
+                if keep_right { ( right.take(), left.take() ) } else { ( left.take(), right.take() ) },
+            CritBitNode::Leaf ( .. ) => unreachable!("Only internal nodes can be collapsed")
+        };
+        match ( kept, dropped ) {
+            ( Some( kept ), Some( dropped ) ) => { *self = *kept; *dropped },
+            _ => unreachable!("Internal nodes should always have both branches filled, what happened?")
+        }
+    }
+
+    fn get_mut( &mut self, key: &K ) -> Option<( &K, &mut V )> {
+        let mut node = self;
+        loop {
+            match *node {
+                CritBitNode::Leaf ( ref k, ref mut v ) => return if *k == *key { Some( ( k, v ) ) } else { None },
+                CritBitNode::Internal ( ( ref mut left, ref mut right ), crit, _ ) => node = match *if key.bit_set( crit ) { right } else { left } {
+                    Some( ref mut kid ) => kid,
+                    None => return None
+                }
+            }
+        }
+    }
+
+    // Validates this subtree bottom-up through an explicit stack, carrying one key up from each
+    // subtree to compare against its sibling's. Each internal node's count must also add up to its
+    // branches' counts.
+    fn check( &self ) -> bool {
+        enum Work<'a, K: CritKey + 'a, V: 'a> {
+            Visit ( &'a CritBitNode<K,V>, Option<usize> ),
+            Join ( usize ),
+        }
+        let mut work = vec![ Work::Visit ( self, None ) ];
+        let mut samples : Vec<&K> = Vec::new();
+        while let Some( item ) = work.pop() {
+            match item {
+                Work::Visit ( node, parent ) => match *node {
+                    CritBitNode::Leaf ( ref k, _ ) => samples.push( k ),
+                    CritBitNode::Internal ( ( Some( ref left ), Some( ref right ) ), crit, len )
+                        if parent.is_none_or( |p| p < crit ) && left.len() + right.len() == len => {
+                            work.push( Work::Join ( crit ) );
+                            work.push( Work::Visit ( right, Some( crit ) ) );
+                            work.push( Work::Visit ( left, Some( crit ) ) );
+                        },
+                    CritBitNode::Internal ( .. ) => return false
+                },
+                Work::Join ( crit ) => {
+                    let r = samples.pop().expect( "Both branches were visited" );
+                    let l = samples.pop().expect( "Both branches were visited" );
+                    if l.crit_bit( r ) != Some( crit ) || l.bit_set( crit ) || ! r.bit_set( crit ) {
+                        return false;
+                    }
+                    samples.push( l );
+                }
+            }
+        }
+        true
+    }
+
+    // Checks just the nodes on one path down: that both branches are there, split on later bits,
+    // and add up to the node's count.
+    #[cfg(debug_assertions)]
+    fn check_path<R: Fn( usize ) -> bool>( &self, right: R ) -> bool {
+        let mut node = self;
+        loop {
+            match *node {
+                CritBitNode::Leaf ( .. ) => return true,
+                CritBitNode::Internal ( ( Some( ref left ), Some( ref kid ) ), crit, len )
+                    if left.splits_below( crit ) && kid.splits_below( crit ) && left.len() + kid.len() == len =>
+                        node = if right( crit ) { kid } else { left },
+                CritBitNode::Internal ( .. ) => return false
+            }
+        }
+    }
+
+    fn splits_below( &self, crit: usize ) -> bool {
+        match *self {
+            CritBitNode::Leaf ( .. ) => true,
+            CritBitNode::Internal ( _, pos, _ ) => pos > crit
+        }
+    }
+
+    fn branch( &self, right: bool ) -> &CritBitNode<K,V> {
+        match *self {
+            CritBitNode::Internal ( ( Some( ref left ), _ ), _, _ ) if ! right => left,
+            CritBitNode::Internal ( ( _, Some( ref right ) ), _, _ ) => right,
+            _ => unreachable!("Only internal nodes have branches, and they should always be filled")
+        }
+    }
+
+    fn branch_mut( &mut self, right: bool ) -> &mut CritBitNode<K,V> {
+        match *self {
+            CritBitNode::Internal ( ( Some( ref mut left ), _ ), _, _ ) if ! right => left,
+            CritBitNode::Internal ( ( _, Some( ref mut right ) ), _, _ ) => right,
+            _ => unreachable!("Only internal nodes have branches, and they should always be filled")
+        }
+    }
+
+    // The entry at `rank` in key order, which must be less than the subtree's size.
+    fn nth( &self, mut rank: usize ) -> ( &K, &V ) {
+        let mut node = self;
+        loop {
+            match *node {
+                CritBitNode::Leaf ( ref k, ref v ) => return ( k, v ),
+                CritBitNode::Internal ( .. ) => {
+                    let left = node.branch( false ).len();
+                    node = if rank < left { node.branch( false ) } else { rank -= left; node.branch( true ) };
+                }
+            }
+        }
+    }
+
+    fn nth_mut( &mut self, mut rank: usize ) -> &mut V {
+        let mut node = self;
+        while let CritBitNode::Internal ( .. ) = *node {
+            let left = node.branch( false ).len();
+            let right = rank >= left;
+            if right {
+                rank -= left;
+            }
+            node = node.branch_mut( right );
+        }
+        node.value_mut()
+    }
+
+    fn first( &self ) -> ( &K, &V ) {
+        self.edge( false )
+    }
+
+    fn last( &self ) -> ( &K, &V ) {
+        self.edge( true )
+    }
+
+    // The leaf at the low (or high) end of the subtree.
+    fn edge( &self, right: bool ) -> ( &K, &V ) {
+        let mut node = self;
+        loop {
+            match *node {
+                CritBitNode::Leaf ( ref k, ref v ) => return ( k, v ),
+                CritBitNode::Internal ( .. ) => node = node.branch( right )
+            }
+        }
+    }
+
+    // The steps taken on the way down towards `key`, and the leaf reached.
+    fn path_to( &self, key: &K ) -> ( Vec<Step<'_, K, V>>, &CritBitNode<K,V> ) {
+        let mut path = Vec::new();
+        let mut node = self;
+        while let CritBitNode::Internal ( _, ref crit, _ ) = *node {
+            let right = key.bit_set( *crit );
+            path.push( ( node, right ) );
+            node = node.branch( right );
+        }
+        ( path, node )
+    }
+
+    fn nearest( &self, key: &K ) -> &K {
+        let mut node = self;
+        loop {
+            match *node {
+                CritBitNode::Leaf ( ref k, _ ) => return k,
+                CritBitNode::Internal ( _, crit, _ ) => node = node.branch( key.bit_set( crit ) )
+            }
+        }
+    }
+
+    // The node reached by taking `turns` down from this one.
+    fn follow( &self, turns: &[bool] ) -> &CritBitNode<K,V> {
+        turns.iter().fold( self, |node, &right| node.branch( right ) )
+    }
+
+    fn follow_mut( &mut self, turns: &[bool] ) -> &mut CritBitNode<K,V> {
+        let mut node = self;
+        for &right in turns {
+            node = node.branch_mut( right );
+        }
+        node
+    }
+
+    // `crit` is the bit where `key` first differs from `nearest( key )`, or `None` if they are equal.
+    // The new leaf is spliced in above the first node that splits on a later bit than that.
+    fn insert<F: FnMut( usize )>( &mut self, key: K, value: V, crit: Option<usize>, observe: &mut F ) -> ( &mut V, Option<V> ) {
+        let mut node = self;
+        while let CritBitNode::Internal ( _, pos, _ ) = *node {
+            if crit.is_some_and( |c| pos >= c ) {
+                break;
+            }
+            node = match *node {
+                CritBitNode::Internal ( ( ref mut left, ref mut right ), pos, ref mut len ) => {
+                    observe( pos );
+                    if crit.is_some() {
+                        *len += 1;
+                    }
+                    match *if key.bit_set( pos ) { right } else { left } {
+                        Some( ref mut kid ) => kid,
+                        None => unreachable!("Internal nodes should always have both branches filled, what happened?")
+                    }
+                },
+                CritBitNode::Leaf ( .. ) => unreachable!("We just checked that this was internal...")
+            };
+        }
+        if let Some( crit ) = crit {
+            observe( crit );
+            let goes_right = key.bit_set( crit );
+            let old = Box::new( std::mem::replace( node, CritBitNode::Internal( ( None, None ), 0, 0 ) ) );
+            let new = Box::new( CritBitNode::Leaf ( key, value ) );
+            let len = old.len() + 1;
+            *node = CritBitNode::Internal (
+                if goes_right { ( Some( old ), Some( new ) ) } else { ( Some( new ), Some( old ) ) },
+                crit,
+                len
+            );
+            match *node {
+                CritBitNode::Internal ( ( _, Some( ref mut new ) ), _, _ ) if   goes_right => ( new.value_mut(), None ),
+                CritBitNode::Internal ( ( Some( ref mut new ), _ ), _, _ ) if ! goes_right => ( new.value_mut(), None ),
+                _ => unreachable!("We just built this internal node...")
+            }
+        } else {
+            let v = node.value_mut();
+            let old = std::mem::replace( v, value );
+            ( v, Some( old ) )
+        }
+    }
+
+    fn value_mut( &mut self ) -> &mut V {
+        match *self {
+            CritBitNode::Leaf ( _, ref mut v ) => v,
+            CritBitNode::Internal ( .. ) => unreachable!("Only leaves hold values")
+        }
+    }
+
+    // Divides the subtree into the keys below `key` and the rest, with `key` itself going below when
+    // `inclusive`. Only the nodes along the path to `key` are rebuilt: the far branches passed on the
+    // way down are stacked up, then joined back onto whichever side they fall on from the bottom up.
+    fn split( self, key: &K, inclusive: bool ) -> ( Option<Self>, Option<Self> ) {
+        let crit = self.nearest( key ).crit_bit( key );
+        let mut passed = Vec::new();
+        let mut node = self;
+        while let CritBitNode::Internal ( _, pos, _ ) = node {
+            if crit.is_some_and( |c| pos >= c ) {
+                break;
+            }
+            node = match node {
+                CritBitNode::Internal ( ( Some( left ), Some( right ) ), _, _ ) => {
+                    let goes_right = key.bit_set( pos );
+                    let ( next, other ) = if goes_right { ( right, left ) } else { ( left, right ) };
+                    passed.push( ( *other, pos, goes_right ) );
+                    *next
+                },
+                _ => unreachable!("Internal nodes should always have both branches filled, what happened?")
+            };
+        }
+        // What's left is `key`'s own leaf, or a subtree wholly on one side of it
+        let below = crit.map_or( inclusive, |c| key.bit_set( c ) );
+        let ( mut low, mut high ) = if below { ( Some( node ), None ) } else { ( None, Some( node ) ) };
+        while let Some( ( other, pos, went_right ) ) = passed.pop() {
+            if went_right {
+                low = Some( match low { Some( low ) => CritBitNode::join( other, low, pos ), None => other } );
+            } else {
+                high = Some( match high { Some( high ) => CritBitNode::join( high, other, pos ), None => other } );
+            }
+        }
+        ( low, high )
+    }
+}
+
+#[test]
+fn verify_bit_at() {
+    assert_eq!( bit_at( &1u8, &0u32 ), false );
+    assert_eq!( bit_at( &128u8, &0u32 ), true );
+    assert_eq!( bit_at( &1u8, &7u32 ), true );
+    assert_eq!( bit_at( &128u8, &7u32 ), false );
+}
+
+#[test]
+fn empty_len() {
+    let t : CritBit<u8,()> = CritBit::new();
+    assert_eq!( t.len(), 0 );
+}
+
+#[test]
+fn empty_contains_key() {
+    let t : CritBit<u8,()> = CritBit::new();
+    assert_eq!( t.contains_key( &0u8 ), false );
+    assert_eq!( t.contains_key( &128u8 ), false );
+    assert_eq!( t.contains_key( &255u8 ), false );
+}
+
+#[test]
+fn empty_get() {
+    let t : CritBit<u8,()> = CritBit::new();
+    assert_eq!( t.get( &0u8 ), None );
+    assert_eq!( t.get( &128u8 ), None );
+    assert_eq!( t.get( &255u8 ), None );
+}
+
+#[test]
+fn empty_get_mut() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    assert!( t.get_mut( &0u8 ).is_none() );
+    assert!( t.get_mut( &128u8 ).is_none() );
+    assert!( t.get_mut( &255u8 ).is_none() );
+}
+
+#[test]
+fn insert_len() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    assert_eq!( t.len(), 0 );
+
+    t.insert( 0u8, () );
+    assert_eq!( t.len(), 1 )
+}
+
+#[test]
+fn insert_contains_key() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    assert_eq!( t.contains_key( &0u8 ), false );
+
+    t.insert( 0u8, () );
+    assert_eq!( t.contains_key( &0u8 ), true );
+}
+
+#[test]
+fn insert_get() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    assert_eq!( t.get( &0u8 ), None );
+
+    t.insert( 0u8, 1u8 );
+    assert_eq!( t.get( &0u8 ), Some ( &1u8 ) );
+}
+
+#[test]
+fn insert_get_mut() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    assert_eq!( t.get( &0u8 ), None );
+
+    t.insert( 0u8, 1u8 );
+    assert_eq!( t.get( &0u8 ), Some ( &1u8 ) );
+
+    *t.get_mut( &0u8 ).unwrap() = 2u8;
+    assert_eq!( t.get( &0u8 ), Some ( &2u8 ) );
+}
+
+#[test]
+fn insert_insert() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    assert_eq!( t.get( &0u8 ), None );
+
+    t.insert( 0u8, 1u8 );
+    assert_eq!( t.get( &0u8 ), Some ( &1u8 ) );
+
+    assert_eq!( t.insert( 0u8, 2u8 ), Some ( 1u8 ) );
+    assert_eq!( t.get( &0u8 ), Some ( &2u8 ) );
+}
+
+#[test]
+fn keys_for_value() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    t.insert( 1u8, 7u8 );
+    t.insert( 2u8, 9u8 );
+    t.insert( 3u8, 7u8 );
+
+    assert_eq!( t.keys_for_value( &7u8 ), vec![ &1u8, &3u8 ] );
+    assert_eq!( t.keys_for_value( &9u8 ), vec![ &2u8 ] );
+    assert!( t.keys_for_value( &0u8 ).is_empty() );
+}
+
+#[test]
+fn iter_with_depth() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    t.insert( 1u8, () );
+    assert_eq!( t.iter_with_depth().collect::<Vec<_>>(), vec![ ( &1u8, &(), 0 ) ] );
+
+    // 2 and 3 differ only in their last bit, so they sit under a split below the root
+    t.insert( 2u8, () );
+    t.insert( 3u8, () );
+    let depths : Vec<_> = t.iter_with_depth().map( |( k, _, d )| ( *k, d ) ).collect();
+    assert_eq!( depths, vec![ ( 1u8, 1 ), ( 2u8, 2 ), ( 3u8, 2 ) ] );
+}
+
+#[test]
+fn intern_values() {
+    let mut t : CritBit<u8,String> = CritBit::new();
+    t.insert( 1u8, "shared".to_string() );
+    t.insert( 2u8, "other".to_string() );
+    t.insert( 3u8, "shared".to_string() );
+
+    let t = t.intern_values();
+    assert_eq!( t.len(), 3 );
+    assert_eq!( **t.get( &1u8 ).unwrap(), "shared" );
+    assert!( Rc::ptr_eq( t.get( &1u8 ).unwrap(), t.get( &3u8 ).unwrap() ) );
+    assert!( ! Rc::ptr_eq( t.get( &1u8 ).unwrap(), t.get( &2u8 ).unwrap() ) );
+}
+
+#[test]
+fn reduce() {
+    let mut t : CritBit<u8,u32> = CritBit::new();
+    assert_eq!( t.reduce( |_, v| *v, |a, b| a + b ), None );
+
+    t.insert( 1u8, 10u32 );
+    t.insert( 2u8, 20u32 );
+    t.insert( 3u8, 30u32 );
+    assert_eq!( t.reduce( |_, v| *v, |a, b| a + b ), Some( 60u32 ) );
+    assert_eq!( t.reduce( |k, _| vec![ *k ], |mut a, b| { a.extend( b ); a } ), Some( vec![ 1u8, 2u8, 3u8 ] ) );
+}
+
+#[test]
+fn insert_traced() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    assert_eq!( t.insert_traced( 0u8, () ), ( None, vec![] ) );
+    assert_eq!( t.insert_traced( 128u8, () ), ( None, vec![ 0 ] ) );
+    assert_eq!( t.insert_traced( 64u8, () ), ( None, vec![ 0, 1 ] ) );
+    assert_eq!( t.insert_traced( 64u8, () ), ( Some( () ), vec![ 0, 1 ] ) );
+
+    // 96 first differs from 64 at bit 2, so the leaf for 64 is split there
+    assert_eq!( t.insert_traced( 96u8, () ), ( None, vec![ 0, 1, 2 ] ) );
+    assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 0u8, 64u8, 96u8, 128u8 ] );
+
+    // 4 lands on 1 but differs from it at bit 5, above the root's split on bit 6, so it becomes the
+    // new root instead of splitting that leaf
+    let mut t : CritBit<u8,()> = CritBit::new();
+    t.insert( 1u8, () );
+    t.insert( 2u8, () );
+    t.insert( 3u8, () );
+    assert_eq!( t.insert_traced( 4u8, () ), ( None, vec![ 5 ] ) );
+    assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 1u8, 2u8, 3u8, 4u8 ] );
+}
+
+#[test]
+fn crit_bit_histogram() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    assert_eq!( t.crit_bit_histogram(), vec![ 0; 8 ] );
+
+    for k in [ 1u8, 2u8, 3u8, 4u8, 5u8 ].iter() {
+        t.insert( *k, () );
+    }
+    // 1, 2, 3 split on bits 6 and 7, 4 and 5 on bit 7, and the two groups on bit 5
+    assert_eq!( t.crit_bit_histogram(), vec![ 0, 0, 0, 0, 0, 1, 1, 2 ] );
+}
+
+#[test]
+fn bulk_get() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    for k in [ 1u8, 2u8, 3u8, 4u8, 16u8 ].iter() {
+        t.insert( *k, *k * 10 );
+    }
+
+    let mut keys = [ 4u8, 16u8, 5u8, 1u8, 4u8 ];
+    assert_eq!( t.bulk_get( &mut keys ), vec![ Some( &40u8 ), Some( &160u8 ), None, Some( &10u8 ), Some( &40u8 ) ] );
+    assert_eq!( keys, [ 1u8, 4u8, 4u8, 5u8, 16u8 ] );
+
+    let empty : CritBit<u8,u8> = CritBit::new();
+    assert_eq!( empty.bulk_get( &mut [ 1u8 ] ), vec![ None ] );
+}
+
+#[test]
+fn try_retain() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    for k in 1u8..7u8 {
+        t.insert( k, k );
+    }
+
+    assert_eq!( t.try_retain( |k, _| if *k < 5 { Ok( *k % 2 == 0 ) } else { Err( *k ) } ), Err( 5u8 ) );
+    assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 2u8, 4u8, 5u8, 6u8 ] );
+
+    assert_eq!( t.try_retain( |_, v| { *v += 1; Ok::<_, ()>( true ) } ), Ok( () ) );
+    assert_eq!( t.get( &6u8 ), Some( &7u8 ) );
+
+    assert_eq!( t.try_retain( |_, _| Ok::<_, ()>( false ) ), Ok( () ) );
+    assert!( t.is_empty() );
+}
+
+#[test]
+fn neighbors() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    for k in [ 10u8, 20u8, 30u8, 40u8, 50u8 ].iter() {
+        t.insert( *k, () );
+    }
+    let keys = |entries: Vec<( &u8, &() )>| entries.into_iter().map( |( k, _ )| *k ).collect::<Vec<_>>();
+
+    assert_eq!( keys( t.neighbors( &30u8, 1, 1 ) ), vec![ 20u8, 30u8, 40u8 ] );
+    assert_eq!( keys( t.neighbors( &30u8, 5, 0 ) ), vec![ 10u8, 20u8, 30u8 ] );
+    assert_eq!( keys( t.neighbors( &35u8, 2, 2 ) ), vec![ 20u8, 30u8, 40u8, 50u8 ] );
+    assert_eq!( keys( t.neighbors( &5u8, 2, 1 ) ), vec![ 10u8 ] );
+    assert_eq!( keys( t.neighbors( &60u8, 0, 3 ) ), vec![] );
+
+    let all = keys( t.iter().collect() );
+    for key in 0..60u8 {
+        for ( before, after ) in [ ( 0, 0 ), ( 1, 2 ), ( 3, 0 ), ( 6, 6 ) ].iter().cloned() {
+            let mut expected : Vec<u8> = all.iter().cloned().filter( |k| *k < key ).rev().take( before ).collect();
+            expected.reverse();
+            expected.extend( all.iter().cloned().filter( |k| *k == key ) );
+            expected.extend( all.iter().cloned().filter( |k| *k > key ).take( after ) );
+            assert_eq!( keys( t.neighbors( &key, before, after ) ), expected );
+        }
+    }
+}
+
+#[test]
+fn rotate_values() {
+    let mut t : CritBit<u8,char> = CritBit::new();
+    for ( k, v ) in [ ( 1u8, 'a' ), ( 2u8, 'b' ), ( 3u8, 'c' ), ( 4u8, 'd' ) ].iter() {
+        t.insert( *k, *v );
+    }
+    let values = |t: &CritBit<u8,char>| t.iter().map( |( _, v )| *v ).collect::<String>();
+
+    t.rotate_values( 1 );
+    assert_eq!( values( &t ), "bcda" );
+    t.rotate_values( -1 );
+    assert_eq!( values( &t ), "abcd" );
+    t.rotate_values( 6 );
+    assert_eq!( values( &t ), "cdab" );
+    assert_eq!( t.get( &1u8 ), Some( &'c' ) );
+
+    let mut empty : CritBit<u8,char> = CritBit::new();
+    empty.rotate_values( 3 );
+    assert!( empty.is_empty() );
+}
+
+#[test]
+fn insert_and_get() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    {
+        let ( v, old ) = t.insert_and_get( 1u8, 10u8 );
+        assert_eq!( ( *v, old ), ( 10u8, None ) );
+    }
+    {
+        let ( v, old ) = t.insert_and_get( 2u8, 20u8 );
+        assert_eq!( ( *v, old ), ( 20u8, None ) );
+        *v += 1;
+    }
+    {
+        let ( v, old ) = t.insert_and_get( 1u8, 30u8 );
+        assert_eq!( ( *v, old ), ( 30u8, Some( 10u8 ) ) );
+        *v += 1;
+    }
+    assert_eq!( t.get( &1u8 ), Some( &31u8 ) );
+    assert_eq!( t.get( &2u8 ), Some( &21u8 ) );
+}
+
+#[test]
+fn compact_key_width() {
+    let mut t : CritBit<u32,()> = CritBit::new();
+    assert_eq!( t.compact_key_width(), 32 );
+
+    t.insert( 0x1234u32, () );
+    assert_eq!( t.compact_key_width(), 32 );
+
+    t.insert( 0x8000u32, () );
+    t.insert( 0xFFFFu32, () );
+    t.insert( 0x0001u32, () );
+    assert_eq!( t.compact_key_width(), 16 );
+
+    t.insert( 0x10000u32, () );
+    assert_eq!( t.compact_key_width(), 15 );
+}
+
+#[test]
+fn merge_disjoint() {
+    let build = |keys: &[u8]| {
+        let mut t : CritBit<u8,u8> = CritBit::new();
+        for k in keys {
+            t.insert( *k, *k );
+        }
+        t
+    };
+    let keys = |t: &CritBit<u8,u8>| t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>();
+
+    let merged = build( &[ 8, 9, 12 ] ).try_merge_disjoint( build( &[ 0, 1, 2, 3 ] ) ).ok().unwrap();
+    assert_eq!( keys( &merged ), vec![ 0, 1, 2, 3, 8, 9, 12 ] );
+    assert_eq!( merged.get( &9 ), Some( &9 ) );
+
+    let merged = build( &[ 0, 1 ] ).merge_disjoint_unchecked( build( &[ 2, 3 ] ) );
+    assert_eq!( keys( &merged ), vec![ 0, 1, 2, 3 ] );
+
+    let merged = build( &[] ).try_merge_disjoint( build( &[ 5 ] ) ).ok().unwrap();
+    assert_eq!( keys( &merged ), vec![ 5 ] );
+
+    // separate ranges, but {2, 8} splits above the bit that separates it from {0, 1}
+    let ( a, b ) = build( &[ 0, 1 ] ).try_merge_disjoint( build( &[ 2, 8 ] ) ).err().unwrap();
+    assert_eq!( ( keys( &a ), keys( &b ) ), ( vec![ 0, 1 ], vec![ 2, 8 ] ) );
+    assert!( build( &[ 0, 2 ] ).try_merge_disjoint( build( &[ 1, 3 ] ) ).is_err() );
+    assert!( build( &[ 1 ] ).try_merge_disjoint( build( &[ 1 ] ) ).is_err() );
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "trees that interleave")]
+fn merge_disjoint_unchecked_rejects_interleaved() {
+    let build = |keys: &[u8]| keys.iter().map( |&k| ( k, () ) ).collect::<CritBit<u8,()>>();
+    build( &[ 0, 2 ] ).merge_disjoint_unchecked( build( &[ 1, 3 ] ) );
+}
+
+#[test]
+fn iter_internal() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    t.insert( 1u8, () );
+    assert_eq!( t.iter_internal().count(), 0 );
+
+    for k in [ 2u8, 3u8, 4u8, 5u8 ].iter() {
+        t.insert( *k, () );
+    }
+    assert_eq!( t.iter_internal().collect::<Vec<_>>(), vec![ ( 5, 3, 2 ), ( 6, 1, 2 ), ( 7, 1, 1 ), ( 7, 1, 1 ) ] );
+    t.remove( &3u8 );
+    assert_eq!( t.iter_internal().collect::<Vec<_>>(), vec![ ( 5, 2, 2 ), ( 6, 1, 1 ), ( 7, 1, 1 ) ] );
+}
+
+#[test]
+fn find_or_successor() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    assert_eq!( t.find_or_successor( &1u8 ), Err( None ) );
+
+    for k in [ 10u8, 20u8, 30u8, 40u8 ].iter() {
+        t.insert( *k, *k + 1 );
+    }
+    assert_eq!( t.find_or_successor( &20u8 ), Ok( ( &20u8, &21u8 ) ) );
+    assert_eq!( t.find_or_successor( &21u8 ), Err( Some( ( &30u8, &31u8 ) ) ) );
+    assert_eq!( t.find_or_successor( &35u8 ), Err( Some( ( &40u8, &41u8 ) ) ) );
+    assert_eq!( t.find_or_successor( &0u8 ), Err( Some( ( &10u8, &11u8 ) ) ) );
+    assert_eq!( t.find_or_successor( &41u8 ), Err( None ) );
+    assert_eq!( t.find_or_successor( &255u8 ), Err( None ) );
+}
+
+#[test]
+fn remove() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    assert_eq!( t.remove( &1u8 ), None );
+
+    t.insert( 1u8, 10u8 );
+    assert_eq!( t.remove( &2u8 ), None );
+    assert_eq!( t.remove( &1u8 ), Some( 10u8 ) );
+    assert!( t.is_empty() );
+
+    for k in 1u8..6u8 {
+        t.insert( k, k * 10 );
+    }
+    assert_eq!( t.remove( &3u8 ), Some( 30u8 ) );
+    assert_eq!( t.remove( &3u8 ), None );
+    assert_eq!( t.remove( &7u8 ), None );
+    assert_eq!( t.len(), 4 );
+    assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 1u8, 2u8, 4u8, 5u8 ] );
+    for k in [ 1u8, 2u8, 4u8, 5u8 ].iter() {
+        assert_eq!( t.get( k ), Some( &( *k * 10 ) ) );
+    }
+}
+
+#[test]
+fn remove_into_pool() {
+    let mut t : CritBit<u8,Vec<u8>> = CritBit::new();
+    t.insert( 1u8, Vec::with_capacity( 64 ) );
+    t.insert( 2u8, vec![ 2u8 ] );
+
+    let mut pool = Vec::new();
+    assert!( t.remove_into_pool( &1u8, &mut pool ) );
+    assert!( ! t.remove_into_pool( &1u8, &mut pool ) );
+    assert_eq!( pool.len(), 1 );
+
+    let mut buffer = pool.pop().unwrap();
+    assert!( buffer.capacity() >= 64 );
+    buffer.push( 3u8 );
+    t.insert( 3u8, buffer );
+    assert_eq!( t.get( &3u8 ), Some( &vec![ 3u8 ] ) );
+    assert_eq!( t.len(), 2 );
+}
+
+#[test]
+fn compare_and_swap() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    t.insert( 1u8, 10u8 );
+
+    assert_eq!( t.compare_and_swap( &1u8, &10u8, 11u8 ), Ok( 10u8 ) );
+    assert_eq!( t.compare_and_swap( &1u8, &10u8, 12u8 ), Err( ( 12u8, Some( &11u8 ) ) ) );
+    assert_eq!( t.compare_and_swap( &2u8, &10u8, 13u8 ), Err( ( 13u8, None ) ) );
+    assert_eq!( t.get( &1u8 ), Some( &11u8 ) );
+    assert!( ! t.contains_key( &2u8 ) );
+}
+
+#[test]
+fn page() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    assert_eq!( t.page( None, 3 ), ( vec![], None ) );
+
+    for k in [ 5u8, 10u8, 15u8, 20u8, 25u8 ].iter() {
+        t.insert( *k, () );
+    }
+    let keys = |page: &Vec<( &u8, &() )>| page.iter().map( |&( k, _ )| *k ).collect::<Vec<_>>();
+
+    let ( first, cursor ) = t.page( None, 3 );
+    assert_eq!( ( keys( &first ), cursor ), ( vec![ 5u8, 10u8, 15u8 ], Some( &15u8 ) ) );
+    let ( second, cursor ) = t.page( cursor, 3 );
+    assert_eq!( ( keys( &second ), cursor ), ( vec![ 20u8, 25u8 ], None ) );
+
+    // cursors don't have to be stored keys
+    assert_eq!( keys( &t.page( Some( &12u8 ), 2 ).0 ), vec![ 15u8, 20u8 ] );
+    assert_eq!( keys( &t.page( Some( &0u8 ), 1 ).0 ), vec![ 5u8 ] );
+    assert_eq!( t.page( Some( &3u8 ), 5 ).1, None );
+    assert_eq!( t.page( Some( &25u8 ), 5 ), ( vec![], None ) );
+}
+
+#[test]
+fn values_sorted() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    for ( k, v ) in [ ( 1u8, 30u8 ), ( 2u8, 10u8 ), ( 3u8, 20u8 ), ( 4u8, 10u8 ) ].iter() {
+        t.insert( *k, *v );
+    }
+    assert_eq!( t.values_sorted(), vec![ &10u8, &10u8, &20u8, &30u8 ] );
+}
+
+#[test]
+fn insert_if_absent() {
+    let mut t : CritBit<u8,Rc<u8>> = CritBit::new();
+    let first = Rc::new( 1u8 );
+    let second = Rc::new( 2u8 );
+
+    assert!( t.insert_if_absent( 1u8, first.clone() ) );
+    assert!( ! t.insert_if_absent( 1u8, second.clone() ) );
+    assert!( Rc::ptr_eq( t.get( &1u8 ).unwrap(), &first ) );
+    assert_eq!( Rc::strong_count( &second ), 1 );
+    assert_eq!( t.len(), 1 );
+}
+
+#[test]
+fn remove_min_max_n() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    for k in [ 3u8, 1u8, 4u8, 2u8 ].iter() {
+        t.insert( *k, *k * 10 );
+    }
+
+    assert_eq!( t.remove_min_n( 2 ), vec![ ( 1u8, 10u8 ), ( 2u8, 20u8 ) ] );
+    assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 3u8, 4u8 ] );
+    assert_eq!( t.get( &3u8 ), Some( &30u8 ) );
+    assert_eq!( t.remove_min_n( 0 ), vec![] );
+
+    for k in [ 5u8, 6u8, 7u8 ].iter() {
+        t.insert( *k, *k * 10 );
+    }
+    assert_eq!( t.remove_max_n( 2 ), vec![ ( 7u8, 70u8 ), ( 6u8, 60u8 ) ] );
+    assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 3u8, 4u8, 5u8 ] );
+
+    assert_eq!( t.remove_max_n( 10 ).len(), 3 );
+    assert!( t.is_empty() );
+}
+
+#[test]
+fn total_value_bytes() {
+    let mut t : CritBit<u8,String> = CritBit::new();
+    assert_eq!( t.total_value_bytes( String::len ), 0 );
+
+    t.insert( 1u8, "one".to_string() );
+    t.insert( 2u8, "three".to_string() );
+    t.insert( 3u8, String::new() );
+    assert_eq!( t.total_value_bytes( String::len ), 8 );
+}
+
+#[test]
+fn split_shards() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    for k in 0u8..100u8 {
+        t.insert( k.wrapping_mul( 37 ), k );
+    }
+
+    let shards = t.split_shards( 4 );
+    assert_eq!( shards.iter().map( CritBit::len ).collect::<Vec<_>>(), vec![ 25, 25, 25, 25 ] );
+    assert!( shards.iter().all( CritBit::is_well_formed ) );
+    let keys : Vec<Vec<u8>> = shards.iter().map( |s| s.iter().map( |( k, _ )| *k ).collect() ).collect();
+    for pair in keys.windows( 2 ) {
+        assert!( pair[ 0 ].last() < pair[ 1 ].first() );
+    }
+    let all : Vec<u8> = keys.concat();
+    let mut expected : Vec<u8> = ( 0u8..100u8 ).map( |k| k.wrapping_mul( 37 ) ).collect();
+    expected.sort();
+    assert_eq!( all, expected );
+
+    let mut small : CritBit<u8,u8> = CritBit::new();
+    small.insert( 1u8, 1u8 );
+    small.insert( 2u8, 2u8 );
+    assert_eq!( small.split_shards( 4 ).len(), 2 );
+    assert!( CritBit::<u8,u8>::new().split_shards( 4 ).is_empty() );
+}
+
+#[test]
+fn merge_many() {
+    let build = |pairs: &[( u8, u32 )]| {
+        let mut t : CritBit<u8,u32> = CritBit::new();
+        for &( k, v ) in pairs {
+            t.insert( k, v );
+        }
+        t
+    };
+    let trees = vec![
+        build( &[ ( 1, 1 ), ( 2, 2 ), ( 5, 5 ) ] ),
+        build( &[ ( 2, 20 ), ( 3, 30 ) ] ),
+        build( &[] ),
+        build( &[ ( 2, 200 ), ( 5, 500 ), ( 9, 900 ) ] ),
+    ];
+
+    let merged = CritBit::merge_many( trees, |_, a, b| a + b );
+    assert_eq!( merged.iter().map( |( k, v )| ( *k, *v ) ).collect::<Vec<_>>(),
+                vec![ ( 1, 1 ), ( 2, 222 ), ( 3, 30 ), ( 5, 505 ), ( 9, 900 ) ] );
+    assert!( merged.is_well_formed() );
+    assert!( CritBit::<u8,u32>::merge_many( vec![], |_, a, _| a ).is_empty() );
+}
+
+#[test]
+fn range_first_last() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    assert_eq!( t.range_first( &0u8, &255u8 ), None );
+
+    for k in [ 10u8, 20u8, 30u8, 40u8 ].iter() {
+        t.insert( *k, () );
+    }
+    let bounds = |lo: u8, hi: u8| ( t.range_first( &lo, &hi ).map( |e| *e.0 ), t.range_last( &lo, &hi ).map( |e| *e.0 ) );
+
+    assert_eq!( bounds( 11, 20 ), ( None, None ) );
+    assert_eq!( bounds( 41, 255 ), ( None, None ) );
+    assert_eq!( bounds( 30, 10 ), ( None, None ) );
+    assert_eq!( bounds( 15, 25 ), ( Some( 20 ), Some( 20 ) ) );
+    assert_eq!( bounds( 20, 21 ), ( Some( 20 ), Some( 20 ) ) );
+    assert_eq!( bounds( 10, 40 ), ( Some( 10 ), Some( 30 ) ) );
+    assert_eq!( bounds( 0, 255 ), ( Some( 10 ), Some( 40 ) ) );
+}
+
+#[test]
+fn count_matching() {
+    let mut t : CritBit<u8,u8> = CritBit::new();
+    assert_eq!( t.count_matching( |_, _| true ), 0 );
+
+    for k in 1u8..11u8 {
+        t.insert( k, k * 10 );
+    }
+    assert_eq!( t.count_matching( |_, v| *v > 55 ), 5 );
+    assert_eq!( t.count_matching( |k, _| *k % 3 == 0 ), 3 );
+}
+
+#[test]
+fn iter_rev() {
+    let mut t : CritBit<u16,u16> = CritBit::new();
+    assert_eq!( t.iter().next_back(), None );
+
+    // a cheap LCG is plenty to scramble the insertion order
+    let mut keys = Vec::new();
+    let mut seed = 12345u32;
+    for _ in 0..300 {
+        seed = seed.wrapping_mul( 1103515245 ).wrapping_add( 12345 );
+        let k = ( seed >> 16 ) as u16;
+        t.insert( k, k.wrapping_add( 1 ) );
+        keys.push( k );
+    }
+    keys.sort();
+    keys.dedup();
+
+    let forward : Vec<u16> = t.iter().map( |( k, _ )| *k ).collect();
+    let mut backward : Vec<u16> = t.iter().rev().map( |( k, _ )| *k ).collect();
+    assert_eq!( forward, keys );
+    backward.reverse();
+    assert_eq!( backward, keys );
+    assert!( t.iter().all( |( k, v )| *v == k.wrapping_add( 1 ) ) );
+
+    // alternating ends meets in the middle without repeating or skipping anything
+    let mut entries = t.iter();
+    let ( mut low, mut high ) = ( Vec::new(), Vec::new() );
+    while let Some( ( k, _ ) ) = entries.next() {
+        low.push( *k );
+        match entries.next_back() { Some( ( k, _ ) ) => high.push( *k ), None => break }
+    }
+    assert_eq!( entries.next(), None );
+    assert_eq!( entries.next_back(), None );
+    high.reverse();
+    low.extend( high );
+    assert_eq!( low, keys );
+}
+
+#[test]
+fn iter_from_rev() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    for k in [ 10u8, 20u8, 30u8, 40u8 ].iter() {
+        t.insert( *k, () );
+    }
+    let keys = |entries: Entries<u8,()>| entries.rev().map( |( k, _ )| *k ).collect::<Vec<_>>();
+
+    assert_eq!( keys( t.iter_from( &20u8, true ) ), vec![ 40u8, 30u8, 20u8 ] );
+    assert_eq!( keys( t.iter_from( &20u8, false ) ), vec![ 40u8, 30u8 ] );
+    assert_eq!( keys( t.iter_from( &25u8, true ) ), vec![ 40u8, 30u8 ] );
+    assert_eq!( keys( t.iter_from( &5u8, false ) ), vec![ 40u8, 30u8, 20u8, 10u8 ] );
+    assert_eq!( keys( t.iter_from( &45u8, true ) ), vec![] );
+}
+
+#[test]
+fn is_well_formed() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    assert!( t.is_well_formed() );
+    for k in [ 7u8, 1u8, 200u8, 4u8, 3u8 ].iter() {
+        t.insert( *k, () );
+        assert!( t.is_well_formed() );
+    }
+
+    let leaf = |k: u8| Some( Box::new( CritBitNode::Leaf ( k, () ) ) );
+    let misordered = CritBit( Some( CritBitNode::Internal ( ( leaf( 2u8 ), leaf( 1u8 ) ), 6, 2 ) ), 2 );
+    assert!( ! misordered.is_well_formed() );
+    let wrong_bit = CritBit( Some( CritBitNode::Internal ( ( leaf( 1u8 ), leaf( 2u8 ) ), 7, 2 ) ), 2 );
+    assert!( ! wrong_bit.is_well_formed() );
+    let stale = CritBit( Some( CritBitNode::Internal ( ( leaf( 1u8 ), None ), 7, 1 ) ), 1 );
+    assert!( ! stale.is_well_formed() );
+    let miscounted = CritBit( Some( CritBitNode::Internal ( ( leaf( 1u8 ), leaf( 2u8 ) ), 6, 3 ) ), 3 );
+    assert!( ! miscounted.is_well_formed() );
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "remove( 00000100 ) left the tree malformed")]
+fn remove_checks_well_formed() {
+    // As if a collapse elsewhere had left a single-branch node behind
+    let stale = CritBitNode::Internal ( ( Some( Box::new( CritBitNode::Leaf ( 1u8, () ) ) ), None ), 6, 1 );
+    let mut t = CritBit( Some( CritBitNode::Internal ( ( Some( Box::new( stale ) ), Some( Box::new( CritBitNode::Leaf ( 4u8, () ) ) ) ), 5, 2 ) ), 2 );
+    t.remove( &4u8 );
+}
+
+#[test]
+fn merge_keys() {
+    let mut a = CritBit::new();
+    let mut b = CritBit::new();
+    for k in [ 1u8, 3u8, 5u8, 200u8 ].iter() { a.insert( *k, () ); }
+    for k in [ 3u8, 4u8, 200u8, 201u8 ].iter() { b.insert( *k, () ); }
+    assert_eq!( a.merge_keys( &b ).collect::<Vec<_>>(), vec![
+        MergeKey::Left ( &1 ), MergeKey::Both ( &3 ), MergeKey::Right ( &4 ), MergeKey::Left ( &5 ),
+        MergeKey::Both ( &200 ), MergeKey::Right ( &201 )
+    ] );
+    assert_eq!( a.merge_keys( &CritBit::new() ).filter( |e| matches!( *e, MergeKey::Left ( _ ) ) ).count(), 4 );
+    assert_eq!( CritBit::<u8,()>::new().merge_keys( &CritBit::new() ).next(), None );
+}
+
+#[test]
+fn set_nth_value() {
+    let mut t = CritBit::new();
+    for k in [ 9u8, 2u8, 130u8, 40u8, 7u8 ].iter() {
+        t.insert( *k, *k );
+    }
+    assert_eq!( t.set_nth_value( 2, 0 ), Some( 9 ) );
+    assert_eq!( t.iter().nth( 2 ), Some( ( &9, &0 ) ) );
+    assert_eq!( t.set_nth_value( 4, 1 ), Some( 130 ) );
+    assert_eq!( t.iter().nth( 4 ), Some( ( &130, &1 ) ) );
+    assert_eq!( t.set_nth_value( 5, 2 ), None );
+    assert_eq!( t.set_nth_value( 0, 3 ), Some( 2 ) );
+    assert_eq!( t.values_sorted(), vec![ &0, &1, &3, &7, &40 ] );
+}
+
+#[test]
+fn split_off_le() {
+    let keys = [ 3u8, 64u8, 65u8, 66u8, 70u8, 128u8, 200u8, 201u8 ];
+    for split in [ 0u8, 3u8, 65u8, 67u8, 128u8, 199u8, 201u8, 255u8 ].iter() {
+        let mut t = CritBit::new();
+        for k in keys.iter() {
+            t.insert( *k, () );
+        }
+        let low = t.split_off_le( split );
+        assert!( low.is_well_formed() && t.is_well_formed() );
+        assert_eq!( low.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), keys.iter().cloned().filter( |k| k <= split ).collect::<Vec<_>>() );
+        assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), keys.iter().cloned().filter( |k| k > split ).collect::<Vec<_>>() );
+    }
+}
+
+#[test]
+fn iter_order() {
+    let mut t : CritBit<u16,u16> = CritBit::new();
+    assert_eq!( t.iter().next(), None );
+    t.insert( 300, 0 );
+    assert_eq!( t.iter().collect::<Vec<_>>(), vec![ ( &300, &0 ) ] );
+
+    let mut keys = vec![ 300u16 ];
+    for i in 1..64u16 {
+        let k = i.wrapping_mul( 40503 );
+        t.insert( k, i );
+        keys.push( k );
+    }
+    keys.sort();
+    assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), keys );
+}
+
+#[test]
+fn upsert() {
+    let mut t = CritBit::new();
+    assert_eq!( t.upsert( 5u8, 'a' ), Upsert::Inserted );
+    assert_eq!( t.upsert( 6u8, 'b' ), Upsert::Inserted );
+    assert_eq!( t.upsert( 5u8, 'c' ), Upsert::Replaced ( 'a' ) );
+    assert_eq!( t.get( &5u8 ), Some( &'c' ) );
+    assert_eq!( t.len(), 2 );
+}
+
+#[test]
+fn iter_mut() {
+    let mut t = CritBit::new();
+    for k in [ 4u8, 1u8, 9u8, 130u8 ].iter() {
+        t.insert( *k, *k as u32 );
+    }
+    assert_eq!( t.iter_mut().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 1u8, 4u8, 9u8, 130u8 ] );
+    for ( _, v ) in t.iter_mut() {
+        *v *= 2;
+    }
+    for k in [ 4u8, 1u8, 9u8, 130u8 ].iter() {
+        assert_eq!( t.get( k ), Some( &( *k as u32 * 2 ) ) );
+    }
+    assert_eq!( CritBit::<u8,u32>::new().iter_mut().next(), None );
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn iter_mut_checks_for_aliasing() {
+    let mut t : CritBit<u8,u32> = ( 0..64u8 ).map( |k| ( k.wrapping_mul( 37 ), 0 ) ).collect();
+    let mut entries = t.iter_mut();
+    let mut step = 0;
+    while let Some( ( _, v ) ) = if step % 3 == 0 { entries.next_back() } else { entries.next() } {
+        *v = step;
+        step += 1;
+    }
+    assert_eq!( ( step, entries.yielded.len() ), ( 64, 64 ) );
+    assert_eq!( t.range_mut( 100u8.. ).count(), t.range( 100u8.. ).count() );
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "handed out the same entry twice")]
+fn assert_no_aliasing_catches_repeats() {
+    let mut yielded = HashSet::new();
+    let leaf = CritBitNode::Leaf ( 1u8, () );
+    assert_no_aliasing( &mut yielded, &leaf as *const CritBitNode<u8,()> as usize );
+    assert_no_aliasing( &mut yielded, &leaf as *const CritBitNode<u8,()> as usize );
+}
+
+#[test]
+fn byte_string_keys() {
+    let mut t = CritBit::new();
+    for ( k, v ) in [ ( "ab", 2 ), ( "b", 3 ), ( "a", 1 ) ].iter() {
+        assert_eq!( t.insert( k.as_bytes().to_vec(), *v ), None );
+    }
+    assert_eq!( t.len(), 3 );
+    assert_eq!( t.get( &b"a".to_vec() ), Some( &1 ) );
+    assert_eq!( t.get( &b"ab".to_vec() ), Some( &2 ) );
+    assert_eq!( t.get( &b"b".to_vec() ), Some( &3 ) );
+    assert_eq!( t.get( &b"abc".to_vec() ), None );
+    assert_eq!( t.get( &b"a\0".to_vec() ), None );
+    assert_eq!( t.iter().map( |( _, v )| *v ).collect::<Vec<_>>(), vec![ 1, 2, 3 ] );
+
+    let mut s : CritBit<&[u8],()> = CritBit::new();
+    for k in [ &b"abc"[..], b"a\0", b"", b"ab", b"a" ].iter() {
+        s.insert( k, () );
+    }
+    assert!( s.is_well_formed() );
+    assert_eq!( s.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ &b""[..], b"a", b"a\0", b"ab", b"abc" ] );
+    assert_eq!( s.remove( &&b"a"[..] ), Some( () ) );
+    assert_eq!( s.get( &&b"a\0"[..] ), Some( &() ) );
+}
+
+#[test]
+fn common_ancestor_bit() {
+    let mut t = CritBit::new();
+    assert_eq!( t.common_ancestor_bit( &1u8, &2u8 ), None );
+    t.insert( 0b0100_0000u8, () );
+    assert_eq!( t.common_ancestor_bit( &1u8, &2u8 ), None );
+    for k in [ 0b0100_0001u8, 0b0100_0110u8, 0b0100_0111u8, 0b1000_0000u8 ].iter() {
+        t.insert( *k, () );
+    }
+    // Both share the 0b0100_0 prefix and part at bit 5
+    assert_eq!( t.common_ancestor_bit( &0b0100_0001u8, &0b0100_0111u8 ), Some( 5 ) );
+    assert_eq!( t.common_ancestor_bit( &0b0100_0110u8, &0b0100_0111u8 ), Some( 7 ) );
+    assert_eq!( t.common_ancestor_bit( &0b0000_0001u8, &0b1100_0000u8 ), Some( 0 ) );
+    // Keys that aren't stored still follow their search paths
+    assert_eq!( t.common_ancestor_bit( &0b0100_0000u8, &0b0000_0000u8 ), Some( 7 ) );
+}
+
+#[test]
+fn longest_prefix_match() {
+    let mut routes : CritBit<&[u8],&str> = CritBit::new();
+    routes.insert( &[], "0.0.0.0/0" );
+    routes.insert( &[ 10 ], "10.0.0.0/8" );
+    routes.insert( &[ 10, 1 ], "10.1.0.0/16" );
+    routes.insert( &[ 192, 168, 1 ], "192.168.1.0/24" );
+    let route = |addr: &[u8]| routes.longest_prefix_match( &addr ).map( |( _, v )| *v );
+    assert_eq!( route( &[ 10, 1, 2, 3 ] ), Some( "10.1.0.0/16" ) );
+    assert_eq!( route( &[ 10, 2, 0, 0 ] ), Some( "10.0.0.0/8" ) );
+    assert_eq!( route( &[ 10, 1 ] ), Some( "10.1.0.0/16" ) );
+    assert_eq!( route( &[ 192, 168, 2, 1 ] ), Some( "0.0.0.0/0" ) );
+    assert_eq!( route( &[ 192, 168, 1, 7 ] ), Some( "192.168.1.0/24" ) );
+
+    let mut t = CritBit::new();
+    t.insert( 4u8, () );
+    t.insert( 5u8, () );
+    assert_eq!( t.longest_prefix_match( &4u8 ), Some( ( &4u8, &() ) ) );
+    assert_eq!( t.longest_prefix_match( &6u8 ), None );
+}
+
+#[test]
+fn from_ranges() {
+    let routes = CritBit::from_ranges( vec![
+        ( vec![ 10, 0, 0, 0 ], 8, "10.0.0.0/8" ),
+        ( vec![ 10, 1, 0, 0 ], 16, "10.1.0.0/16" ),
+        ( vec![ 10, 1, 2, 0 ], 24, "10.1.2.0/24" ),
+        ( vec![ 0, 0, 0, 0 ], 0, "0.0.0.0/0" ),
+    ] );
+    assert_eq!( routes.len(), 4 );
+    let route = |addr: &[u8]| routes.longest_prefix_match( &addr.to_vec() ).map( |( _, v )| *v );
+    assert_eq!( route( &[ 10, 1, 2, 3 ] ), Some( "10.1.2.0/24" ) );
+    assert_eq!( route( &[ 10, 1, 9, 9 ] ), Some( "10.1.0.0/16" ) );
+    assert_eq!( route( &[ 10, 7, 0, 1 ] ), Some( "10.0.0.0/8" ) );
+    assert_eq!( route( &[ 172, 16, 0, 1 ] ), Some( "0.0.0.0/0" ) );
+}
+
+#[test]
+#[should_panic(expected="no /12 prefix made of whole bytes")]
+fn from_ranges_rejects_partial_bytes() {
+    CritBit::from_ranges( vec![ ( vec![ 172, 16, 0, 0 ], 12, () ) ] );
+}
+
+#[test]
+fn prefix_matches() {
+    let mut t : CritBit<&[u8],u32> = CritBit::new();
+    for ( k, v ) in [ ( &b"abcd"[..], 4 ), ( b"a", 1 ), ( b"abx", 3 ), ( b"ab", 2 ), ( b"b", 5 ), ( b"abcdefg", 7 ) ].iter() {
+        t.insert( k, *v );
+    }
+    let values = |key: &[u8]| t.prefix_matches( key ).into_iter().map( |( _, v )| *v ).collect::<Vec<_>>();
+    assert_eq!( values( b"abcdef" ), vec![ 1, 2, 4 ] );
+    assert_eq!( values( b"abcdefg" ), vec![ 1, 2, 4, 7 ] );
+    assert_eq!( values( b"ab" ), vec![ 1, 2 ] );
+    assert_eq!( values( b"c" ), Vec::<u32>::new() );
+    t.insert( b"", 0 );
+    assert_eq!( t.prefix_matches( b"abc" ).into_iter().map( |( k, _ )| k.len() ).collect::<Vec<_>>(), vec![ 0, 1, 2 ] );
+}
+
+#[test]
+fn iter_mut_references_stay_valid() {
+    let mut t = CritBit::new();
+    for k in 0..32u8 {
+        t.insert( k.wrapping_mul( 37 ), k as u32 );
+    }
+    let mut iter = t.iter_mut();
+    let ( _, first ) = iter.next().unwrap();
+    let mut held = vec![ first ];
+    for ( _, v ) in iter.by_ref() {
+        *held[ 0 ] += *v;
+        *v += 100;
+        held.push( v );
+    }
+    for v in held.iter_mut() {
+        **v += 1;
+    }
+    assert_eq!( t.iter().next(), Some( ( &0u8, &( ( 1..32 ).sum::<u32>() + 1 ) ) ) );
+    assert!( t.iter().skip( 1 ).all( |( k, v )| *v == ( 0..32u8 ).find( |x| x.wrapping_mul( 37 ) == *k ).unwrap() as u32 + 101 ) );
+}
+
+#[test]
+fn iter_prefix() {
+    let mut t = CritBit::new();
+    for ( k, v ) in [ ( "apply", 2 ), ( "banana", 3 ), ( "apple", 1 ), ( "app", 0 ), ( "appla", 4 ) ].iter() {
+        t.insert( k.as_bytes().to_vec(), *v );
+    }
+    let values = |prefix: &str| t.iter_prefix( &prefix.as_bytes().to_vec() ).map( |( _, v )| *v ).collect::<Vec<_>>();
+    assert_eq!( values( "appl" ), vec![ 4, 1, 2 ] );
+    assert_eq!( values( "apple" ), vec![ 1 ] );
+    assert_eq!( values( "app" ), vec![ 0, 4, 1, 2 ] );
+    assert_eq!( values( "" ), vec![ 0, 4, 1, 2, 3 ] );
+    assert_eq!( values( "apr" ), Vec::<i32>::new() );
+    assert_eq!( values( "bananas" ), Vec::<i32>::new() );
+    assert_eq!( t.iter_prefix( &b"appl".to_vec() ).next_back(), Some( ( &b"apply".to_vec(), &2 ) ) );
+
+    let mut n = CritBit::new();
+    n.insert( 3u8, () );
+    n.insert( 9u8, () );
+    assert_eq!( n.iter_prefix( &3u8 ).count(), 1 );
+    assert_eq!( n.iter_prefix( &4u8 ).count(), 0 );
+}
+
+#[test]
+fn common_prefix() {
+    let mut t : CritBit<&[u8],()> = CritBit::new();
+    assert_eq!( t.common_prefix(), Vec::<u8>::new() );
+    t.insert( b"k8/pods", () );
+    assert_eq!( t.common_prefix(), b"k8/pods".to_vec() );
+    t.insert( b"k8/nodes", () );
+    t.insert( b"k8s", () );
+    assert_eq!( t.common_prefix(), b"k8".to_vec() );
+    // A key that ends where the others carry on is still shared in full
+    t.insert( b"k8", () );
+    assert_eq!( t.common_prefix(), b"k8".to_vec() );
+    t.insert( b"v1", () );
+    assert_eq!( t.common_prefix(), Vec::<u8>::new() );
+}
+
+#[test]
+fn rollup_counts() {
+    let t : CritBit<Vec<u8>,()> = [ "a/1", "b/1", "a/2", "a/3/x", "b/2", "a" ].iter().map( |k| ( k.as_bytes().to_vec(), () ) ).collect();
+    let counts = t.rollup_counts( 1 );
+    assert!( counts.is_well_formed() );
+    assert_eq!( counts.iter().map( |( k, n )| ( &k[ .. ], *n ) ).collect::<Vec<_>>(), vec![ ( &b"a"[ .. ], 4 ), ( b"b", 2 ) ] );
+    let counts = t.rollup_counts( 3 );
+    assert_eq!( counts.iter().map( |( k, n )| ( &k[ .. ], *n ) ).collect::<Vec<_>>(),
+                vec![ ( &b"a"[ .. ], 1 ), ( b"a/1", 1 ), ( b"a/2", 1 ), ( b"a/3", 1 ), ( b"b/1", 1 ), ( b"b/2", 1 ) ] );
+    assert_eq!( t.rollup_counts( 0 ).iter().collect::<Vec<_>>(), vec![ ( &Vec::new(), &6 ) ] );
+    assert!( CritBit::<&[u8],()>::new().rollup_counts( 2 ).is_empty() );
+}
+
+#[test]
+fn iter_bytes() {
+    let t : CritBit<Vec<u8>,usize> = [ "pear", "fig", "peach" ].iter().map( |k| ( k.as_bytes().to_vec(), k.len() ) ).collect();
+    assert!( t.iter_bytes().zip( t.iter() ).all( |( ( bytes, a ), ( k, b ) )| bytes == &k[ .. ] && a == b ) );
+    assert_eq!( t.iter_bytes().map( |( k, _ )| k ).collect::<Vec<_>>(), vec![ &b"fig"[ .. ], b"peach", b"pear" ] );
+    assert_eq!( t.iter_bytes().next_back(), Some( ( &b"pear"[ .. ], &4 ) ) );
+    assert_eq!( CritBit::<&[u8],()>::new().iter_bytes().next(), None );
+}
+
+#[test]
+fn retain_keys() {
+    let mut t : CritBit<Vec<u8>,usize> = [ "tmp/a", "etc/hosts", "tmp/b/c", "tmpfile", "var/log" ].iter()
+        .enumerate().map( |( v, k )| ( k.as_bytes().to_vec(), v ) ).collect();
+    t.retain_keys( |k| ! k.starts_with( b"tmp/" ) );
+    assert!( t.is_well_formed() );
+    assert_eq!( t.len(), 3 );
+    assert_eq!( t.iter_bytes().map( |( k, _ )| k ).collect::<Vec<_>>(), vec![ &b"etc/hosts"[ .. ], b"tmpfile", b"var/log" ] );
+    t.retain_keys( |_| false );
+    assert!( t.is_empty() );
+}
+
+#[test]
+fn collect_prefix_into() {
+    let mut t : CritBit<&[u8],u32> = CritBit::new();
+    for ( k, v ) in [ ( &b"car"[..], 1 ), ( b"cart", 2 ), ( b"cat", 3 ), ( b"dog", 4 ) ].iter() {
+        t.insert( k, *v );
+    }
+    let mut out = Vec::new();
+    t.collect_prefix_into( b"car", &mut out );
+    assert_eq!( out.iter().map( |&( _, v )| *v ).collect::<Vec<_>>(), vec![ 1, 2 ] );
+    t.collect_prefix_into( b"ca", &mut out );
+    assert_eq!( out.iter().map( |&( _, v )| *v ).collect::<Vec<_>>(), vec![ 1, 2, 3 ] );
+    t.collect_prefix_into( b"cb", &mut out );
+    assert!( out.is_empty() );
+    t.collect_prefix_into( b"", &mut out );
+    assert_eq!( out.len(), 4 );
+}
+
+#[test]
+fn prefix_min_max() {
+    let mut t = CritBit::new();
+    for k in [ "user/7", "user/3", "user/12", "group/1", "users" ].iter() {
+        t.insert( k.as_bytes().to_vec(), () );
+    }
+    assert_eq!( t.prefix_min( b"user/" ).map( |( k, _ )| &k[ .. ] ), Some( &b"user/12"[ .. ] ) );
+    assert_eq!( t.prefix_max( b"user/" ).map( |( k, _ )| &k[ .. ] ), Some( &b"user/7"[ .. ] ) );
+    assert_eq!( t.prefix_min( b"users" ), t.prefix_max( b"users" ) );
+    assert_eq!( t.prefix_min( b"" ).map( |( k, _ )| &k[ .. ] ), Some( &b"group/1"[ .. ] ) );
+    assert_eq!( t.prefix_max( b"u" ).map( |( k, _ )| &k[ .. ] ), Some( &b"users"[ .. ] ) );
+    assert_eq!( t.prefix_min( b"role/" ), None );
+    assert_eq!( t.prefix_max( b"user/70" ), None );
+}
 
-pub struct CritBit<K,V>( Option<CritBitNode<K,V>> ) where K: PrimInt;
+#[test]
+fn keep_only_prefix() {
+    let mut t : CritBit<Vec<u8>,usize> = [ "src/lib.rs", "src/arena.rs", "Cargo.toml", "src", "tests/a.rs" ].iter()
+        .enumerate().map( |( v, k )| ( k.as_bytes().to_vec(), v ) ).collect();
+    t.keep_only_prefix( b"src/" );
+    assert!( t.is_well_formed() );
+    assert_eq!( t.len(), 2 );
+    assert_eq!( t.values().cloned().collect::<Vec<_>>(), vec![ 1, 0 ] );
+    t.keep_only_prefix( b"src/lib.rs" );
+    assert_eq!( t.keys().collect::<Vec<_>>(), vec![ &b"src/lib.rs".to_vec() ] );
+    t.keep_only_prefix( b"src/arena" );
+    assert!( t.is_empty() );
+    assert_eq!( t.len(), 0 );
+    t.keep_only_prefix( b"" );
+    assert!( t.is_empty() );
+}
 
-pub enum CritBitNode<K,V> where K: PrimInt {
-    Leaf ( K, V ),
-    Internal ( (Option<Box<CritBitNode<K,V>>>, Option<Box<CritBitNode<K,V>>>), u32 ),
+#[test]
+fn from_pairs() {
+    let pairs = || vec![ ( 3u8, 'a' ), ( 1u8, 'b' ), ( 3u8, 'c' ), ( 2u8, 'd' ), ( 3u8, 'e' ) ];
+    let last = CritBit::from_pairs_last( pairs() );
+    assert_eq!( last.iter().map( |( k, v )| ( *k, *v ) ).collect::<Vec<_>>(), vec![ ( 1, 'b' ), ( 2, 'd' ), ( 3, 'e' ) ] );
+    let first = CritBit::from_pairs_first( pairs() );
+    assert_eq!( first.iter().map( |( k, v )| ( *k, *v ) ).collect::<Vec<_>>(), vec![ ( 1, 'b' ), ( 2, 'd' ), ( 3, 'a' ) ] );
 }
 
-#[inline(always)]
-fn bit_at<T: PrimInt>( value: &T, pos: &u32 ) -> bool {
-    value.rotate_left(*pos).leading_zeros() == 0
+#[test]
+fn min_max() {
+    let mut t = CritBit::new();
+    assert_eq!( ( t.min(), t.max() ), ( None, None ) );
+    t.insert( 40u8, 'a' );
+    assert_eq!( ( t.min(), t.max() ), ( Some( ( &40, &'a' ) ), Some( ( &40, &'a' ) ) ) );
+    for ( k, v ) in [ ( 7u8, 'b' ), ( 200u8, 'c' ), ( 41u8, 'd' ), ( 6u8, 'e' ) ].iter() {
+        t.insert( *k, *v );
+    }
+    assert_eq!( t.min(), Some( ( &6, &'e' ) ) );
+    assert_eq!( t.max(), Some( ( &200, &'c' ) ) );
 }
 
-impl<K,V> CritBit<K,V> where K: PrimInt {
-    pub fn new() -> CritBit<K,V> {
-        CritBit( None )
+#[test]
+fn median_key() {
+    let mut t = CritBit::new();
+    assert_eq!( t.median_key(), None );
+    for k in [ 50u8, 3u8, 200u8, 17u8, 90u8 ].iter() {
+        t.insert( *k, () );
     }
+    assert_eq!( t.median_key(), Some( &50 ) );
+    t.insert( 1u8, () );
+    // Six entries: 1 3 17 50 90 200, so the upper middle one
+    assert_eq!( t.median_key(), Some( &50 ) );
+    t.insert( 255u8, () );
+    assert_eq!( t.median_key(), Some( &50 ) );
+    t.insert( 254u8, () );
+    assert_eq!( t.median_key(), Some( &90 ) );
 
-    pub fn clear( &mut self ) {
-        self.0 = None;
+    // The counts steering the descent follow removals too
+    for k in [ 1u8, 200u8, 3u8 ].iter() {
+        t.remove( k );
+        assert_eq!( t.median_key(), t.keys().nth( t.len() / 2 ) );
     }
+    assert_eq!( t.median_key(), Some( &90 ) );
+}
 
-    pub fn len( &self ) -> usize {
-        self.0.iter().map(CritBitNode::len).fold(0, Add::add)
+#[test]
+fn floor_ceiling() {
+    let mut t = CritBit::new();
+    assert_eq!( t.floor( &5u8 ), None );
+    for k in [ 10u8, 20u8, 30u8 ].iter() {
+        t.insert( *k, () );
     }
+    assert_eq!( t.floor( &25 ), Some( ( &20, &() ) ) );
+    assert_eq!( t.ceiling( &25 ), Some( ( &30, &() ) ) );
+    assert_eq!( t.floor( &30 ), Some( ( &30, &() ) ) );
+    assert_eq!( t.ceiling( &30 ), Some( ( &30, &() ) ) );
+    assert_eq!( t.ceiling( &5 ), Some( ( &10, &() ) ) );
+    assert_eq!( t.floor( &9 ), None );
+    assert_eq!( t.ceiling( &31 ), None );
+    assert_eq!( t.floor( &255 ), Some( ( &30, &() ) ) );
+}
 
-    pub fn get( &self, key: &K ) -> Option<&V> {
-        match &self.0 {
-            &Some( ref node ) => node.get( key ),
-            &None => None
+#[test]
+fn remove_returning_successor() {
+    let mut t = CritBit::new();
+    for k in [ 10u8, 20u8, 30u8, 40u8 ].iter() {
+        t.insert( *k, *k );
+    }
+    assert_eq!( t.remove_returning_successor( &20 ), ( Some( 20 ), Some( 30 ) ) );
+    assert_eq!( t.remove_returning_successor( &25 ), ( None, Some( 30 ) ) );
+    assert_eq!( t.remove_returning_successor( &40 ), ( Some( 40 ), None ) );
+    assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 10, 30 ] );
+
+    let mut cursor = t.min().map( |( k, _ )| *k );
+    while let Some( k ) = cursor {
+        cursor = t.remove_returning_successor( &k ).1;
+    }
+    assert!( t.is_empty() );
+}
+
+#[test]
+fn remove_leaves_minimal_structure() {
+    for gone in [ 1u8, 2u8, 200u8 ].iter() {
+        let mut t = CritBit::new();
+        for k in [ 1u8, 2u8, 200u8 ].iter() {
+            t.insert( *k, *k );
+        }
+        assert_eq!( t.remove( gone ), Some( *gone ) );
+        assert_eq!( t.len(), 2 );
+        assert!( t.is_well_formed() );
+        assert_eq!( t.iter_internal().count(), 1 );
+        for k in [ 1u8, 2u8, 200u8 ].iter().filter( |k| *k != gone ) {
+            assert_eq!( t.get( k ), Some( k ) );
         }
     }
+}
 
-    pub fn get_mut( &mut self, key: &K ) -> Option<&mut V> {
-        match &mut self.0 {
-            &mut Some( ref mut node ) => node.get_mut( key ),
-            &mut None => None
+#[test]
+fn wide_key_bits() {
+    let top64 = 1u64 << 63;
+    assert!( top64.bit_set( 0 ) && ! top64.bit_set( 1 ) && ! top64.bit_set( 63 ) );
+    assert!( 1u64.bit_set( 63 ) && ! 1u64.bit_set( 62 ) && ! 1u64.bit_set( 0 ) );
+    assert!( ( 1u64 << 32 ).bit_set( 31 ) && ! ( 1u64 << 32 ).bit_set( 32 ) );
+    assert!( ! u64::MAX.bit_set( 64 ) );
+
+    let top128 = 1u128 << 127;
+    assert!( top128.bit_set( 0 ) && ! top128.bit_set( 127 ) );
+    assert!( 1u128.bit_set( 127 ) && ! 1u128.bit_set( 126 ) && ! 1u128.bit_set( 0 ) );
+    assert!( ( 1u128 << 64 ).bit_set( 63 ) && ! ( 1u128 << 64 ).bit_set( 64 ) );
+    assert!( ! u128::MAX.bit_set( 128 ) );
+
+    let low = 0x1234u128;
+    let high = low | top128;
+    assert_eq!( low.crit_bit( &high ), Some( 0 ) );
+    let mut t = CritBit::new();
+    assert_eq!( t.insert_traced( low, 'l' ).1, Vec::<usize>::new() );
+    assert_eq!( t.insert_traced( high, 'h' ).1, vec![ 0 ] );
+    assert_eq!( t.iter_internal().collect::<Vec<_>>(), vec![ ( 0, 1, 1 ) ] );
+    assert_eq!( ( t.get( &low ), t.get( &high ) ), ( Some( &'l' ), Some( &'h' ) ) );
+}
+
+#[test]
+fn insertion_order_independent() {
+    fn permute( keys: &mut Vec<u8>, k: usize, check: &mut dyn FnMut( &[u8] ) ) {
+        if k == keys.len() {
+            return check( keys );
+        }
+        for i in k..keys.len() {
+            keys.swap( k, i );
+            permute( keys, k + 1, check );
+            keys.swap( k, i );
         }
     }
+    let mut orders = 0;
+    permute( &mut vec![ 0u8, 1u8, 2u8, 3u8, 128u8, 255u8 ], 0, &mut |order| {
+        let mut t = CritBit::new();
+        for k in order {
+            t.insert( *k, () );
+        }
+        assert!( t.is_well_formed() );
+        assert!( order.iter().all( |k| t.contains_key( k ) ), "lost a key inserting {:?}", order );
+        assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 0u8, 1u8, 2u8, 3u8, 128u8, 255u8 ] );
+        orders += 1;
+    } );
+    assert_eq!( orders, 720 );
+}
 
-    pub fn contains_key( &self, key: &K ) -> bool {
-        self.get( key ).is_some()
+#[test]
+fn into_sorted_vec() {
+    let mut t = CritBit::new();
+    for k in [ 30u8, 7u8, 200u8, 8u8, 100u8, 31u8, 1u8 ].iter() {
+        t.insert( *k, *k as u32 * 2 );
     }
+    let sorted = t.into_sorted_vec();
+    assert_eq!( sorted.len(), 7 );
+    assert_eq!( sorted.capacity(), 7 );
+    assert_eq!( sorted, vec![ ( 1, 2 ), ( 7, 14 ), ( 8, 16 ), ( 30, 60 ), ( 31, 62 ), ( 100, 200 ), ( 200, 400 ) ] );
+    assert_eq!( CritBit::<u8,u32>::new().into_sorted_vec(), vec![] );
+}
 
-    pub fn insert( &mut self, key: K, value: V ) -> Option<V> {
-        match &mut self.0 {
-            &mut Some( ref mut node ) => node.insert( key, value ),
-            x => { std::mem::replace( x, Some( CritBitNode::Leaf( key, value ) ) ); None }
-        }
+#[test]
+fn clone_is_independent() {
+    let mut t = CritBit::new();
+    for k in [ 5u8, 90u8, 6u8, 250u8, 1u8 ].iter() {
+        t.insert( *k, vec![ *k ] );
     }
+    let snapshot = t.clone();
+    t.insert( 5u8, vec![ 0 ] );
+    t.insert( 7u8, vec![ 7 ] );
+    t.remove( &250u8 );
+    t.get_mut( &1u8 ).unwrap().push( 2 );
+    assert!( snapshot.is_well_formed() );
+    assert_eq!( snapshot.iter().map( |( k, v )| ( *k, v.clone() ) ).collect::<Vec<_>>(),
+                vec![ ( 1, vec![ 1 ] ), ( 5, vec![ 5 ] ), ( 6, vec![ 6 ] ), ( 90, vec![ 90 ] ), ( 250, vec![ 250 ] ) ] );
+    assert!( CritBit::<u8,()>::new().clone().is_empty() );
 }
 
-impl<K: PrimInt, V> CritBitNode<K, V> {
-    fn len( &self ) -> usize {
-        match *self {
-            CritBitNode::Leaf ( .. ) => 1,
-            CritBitNode::Internal( ( ref left, ref right ), _ ) => {
-                left.iter().chain(right.iter()).map(|x| x.len()).fold(0, Add::add)
-            }
-        }
+#[test]
+fn map_equality() {
+    use std::collections::hash_map::DefaultHasher;
+    let hash = |t: &CritBit<u8,char>| { let mut h = DefaultHasher::new(); t.hash( &mut h ); h.finish() };
+    let mut a = CritBit::new();
+    let mut b = CritBit::new();
+    for k in [ 1u8, 2u8, 3u8 ].iter() { a.insert( *k, 'x' ); }
+    for k in [ 3u8, 1u8, 2u8 ].iter() { b.insert( *k, 'x' ); }
+    assert!( a == b );
+    assert_eq!( hash( &a ), hash( &b ) );
+    b.insert( 2u8, 'y' );
+    assert!( a != b );
+    b.insert( 2u8, 'x' );
+    b.insert( 4u8, 'x' );
+    assert!( a != b );
+    assert!( CritBit::<u8,char>::new() == CritBit::new() );
+}
+
+#[test]
+fn debug_shows_shape() {
+    let mut t = CritBit::new();
+    assert_eq!( format!( "{:?}", t ), "Empty" );
+    t.insert( 2u8, 'b' );
+    assert_eq!( format!( "{:?}", t ), "Leaf( 2 => 'b' )" );
+    t.insert( 3u8, 'c' );
+    assert_eq!( format!( "{:?}", t ), "Internal( bit 7 )\n  Leaf( 2 => 'b' )\n  Leaf( 3 => 'c' )" );
+    t.insert( 128u8, 'x' );
+    assert_eq!( format!( "{:?}", t ), "Internal( bit 0 )\n  Internal( bit 7 )\n    Leaf( 2 => 'b' )\n    Leaf( 3 => 'c' )\n  Leaf( 128 => 'x' )" );
+}
+
+#[test]
+fn new_and_default() {
+    assert_eq!( CritBit::<u8,u8>::new().len(), 0 );
+    let d : CritBit<u8,u8> = Default::default();
+    assert!( d.is_empty() );
+    assert!( d == CritBit::new() );
+    let mut t = CritBit::new();
+    t.insert( 1u16, "inferred" );
+    assert_eq!( t.len(), 1 );
+}
+
+// Every key is a prefix of the next, so each internal node has a leaf on the left and the rest of
+// the chain on the right, as deep as `bytes` is long.
+#[cfg(test)]
+fn deep_chain( bytes: &[u8] ) -> CritBit<&[u8], usize> {
+    let depth = bytes.len();
+    let mut node = CritBitNode::Leaf ( bytes, depth );
+    for i in ( 0..depth ).rev() {
+        node = CritBitNode::join( CritBitNode::Leaf ( &bytes[ ..i ], i ), node, i * 9 );
+    }
+    CritBit( Some( node ), depth + 1 )
+}
+
+#[test]
+fn lookup_deep_chain() {
+    let depth = 100000;
+    let bytes = vec![ 0u8; depth ];
+    let mut t = deep_chain( &bytes );
+    assert_eq!( t.get( &&bytes[ ..depth ] ), Some( &depth ) );
+    assert_eq!( t.get( &&bytes[ ..depth / 2 ] ), Some( &( depth / 2 ) ) );
+    *t.get_mut( &&bytes[ ..depth - 1 ] ).unwrap() = 0;
+    assert_eq!( t.get( &&bytes[ ..depth - 1 ] ), Some( &0 ) );
+    assert_eq!( t.get( &&[ 1u8 ][ .. ] ), None );
+    *t.entry( &bytes[ ..depth / 3 ] ).or_insert( 0 ) += 1;
+    assert_eq!( t.get( &&bytes[ ..depth / 3 ] ), Some( &( depth / 3 + 1 ) ) );
+}
+
+#[test]
+fn insert_deep_chain() {
+    let depth = 100000;
+    let bytes = vec![ 0u8; depth ];
+    let mut deeper = bytes.clone();
+    deeper.push( 1u8 );
+    let mut t = deep_chain( &bytes );
+    assert_eq!( t.insert( &bytes[ ..depth - 1 ], 0 ), Some( depth - 1 ) );
+    // Spliced in below the bottom of the chain
+    assert_eq!( t.insert( &deeper[ .. ], 1 ), None );
+    assert_eq!( t.get( &&deeper[ .. ] ), Some( &1 ) );
+    assert_eq!( t.len(), depth + 2 );
+    assert_eq!( t.min(), Some( ( &&bytes[ ..0 ], &0 ) ) );
+    assert_eq!( t.max(), Some( ( &&deeper[ .. ], &1 ) ) );
+    assert_eq!( t.median_key().map( |k| k.len() ), Some( depth / 2 + 1 ) );
+    assert_eq!( t.iter().count(), depth + 2 );
+}
+
+#[test]
+fn remove_and_split_deep_chain() {
+    let depth = 100000;
+    let bytes = vec![ 0u8; depth ];
+    let mut t = deep_chain( &bytes );
+    assert_eq!( t.remove( &&bytes[ ..depth ] ), Some( depth ) );
+    assert_eq!( t.remove( &&bytes[ ..depth / 2 ] ), Some( depth / 2 ) );
+    assert_eq!( t.remove( &&bytes[ ..depth / 2 ] ), None );
+    assert_eq!( t.len(), depth - 1 );
+    assert_eq!( t.max(), Some( ( &&bytes[ ..depth - 1 ], &( depth - 1 ) ) ) );
+
+    let mut high = t.split_off( &&bytes[ ..depth / 4 ] );
+    assert_eq!( ( t.len(), high.len() ), ( depth / 4, depth - 1 - depth / 4 ) );
+    assert_eq!( t.max().map( |( k, _ )| k.len() ), Some( depth / 4 - 1 ) );
+    assert_eq!( high.min().map( |( k, _ )| k.len() ), Some( depth / 4 ) );
+    let middle = high.split_off_le( &&bytes[ ..depth / 2 ] );
+    assert_eq!( middle.len(), depth / 4 );
+    assert_eq!( middle.max().map( |( k, _ )| k.len() ), Some( depth / 2 - 1 ) );
+    assert_eq!( high.min().map( |( k, _ )| k.len() ), Some( depth / 2 + 1 ) );
+}
+
+#[test]
+fn pop_deep_chain() {
+    let depth = 100000;
+    let bytes = vec![ 0u8; depth ];
+    let mut t = deep_chain( &bytes );
+    assert_eq!( t.pop_last(), Some( ( &bytes[ ..depth ], depth ) ) );
+    assert_eq!( t.pop_first(), Some( ( &bytes[ ..0 ], 0 ) ) );
+    let values = |entries: Vec<( &[u8], usize )>| entries.into_iter().map( |( _, v )| v ).collect::<Vec<_>>();
+    assert_eq!( values( t.remove_max_n( 3 ) ), vec![ depth - 1, depth - 2, depth - 3 ] );
+    assert_eq!( values( t.remove_min_n( 2 ) ), vec![ 1, 2 ] );
+    assert_eq!( t.len(), depth - 6 );
+    assert_eq!( ( t.min().map( |( _, v )| *v ), t.max().map( |( _, v )| *v ) ), ( Some( 3 ), Some( depth - 4 ) ) );
+}
+
+#[test]
+fn drop_deep_chain() {
+    let bytes = vec![ 0u8; 100000 ];
+    drop( deep_chain( &bytes ) );
+    let mut t = deep_chain( &bytes );
+    t.clear();
+    assert!( t.is_empty() );
+}
+
+#[test]
+fn into_iter_deep_chain() {
+    let bytes = vec![ 0u8; 100000 ];
+    let values : Vec<usize> = deep_chain( &bytes ).into_iter().map( |( _, v )| v ).collect();
+    assert_eq!( values, ( 0..=bytes.len() ).collect::<Vec<_>>() );
+    let mut partial = deep_chain( &bytes ).into_iter();
+    assert_eq!( partial.nth( 10 ).map( |( k, _ )| k.len() ), Some( 10 ) );
+    drop( partial );
+}
+
+#[test]
+fn retain_deep_chain() {
+    let bytes = vec![ 0u8; 100000 ];
+    let mut t = deep_chain( &bytes );
+    t.retain( |k, _| k.len() % 2 == 0 );
+    assert_eq!( t.len(), 50001 );
+    assert_eq!( t.get( &&bytes[ ..500 ] ), Some( &500 ) );
+    assert_eq!( t.get( &&bytes[ ..501 ] ), None );
+    t.retain( |k, _| k.is_empty() );
+    assert_eq!( t.keys().collect::<Vec<_>>(), vec![ &&bytes[ ..0 ] ] );
+    deep_chain( &bytes ).retain( |_, _| false );
+}
+
+#[test]
+fn returning_len() {
+    let mut t = CritBit::new();
+    assert_eq!( t.insert_returning_len( 3u8, 'a' ), ( None, 1 ) );
+    assert_eq!( t.insert_returning_len( 1u8, 'b' ), ( None, 2 ) );
+    assert_eq!( t.insert_returning_len( 3u8, 'c' ), ( Some( 'a' ), 2 ) );
+    assert_eq!( t.insert_returning_len( 200u8, 'd' ), ( None, 3 ) );
+    assert_eq!( t.remove_returning_len( &1u8 ), ( Some( 'b' ), 2 ) );
+    assert_eq!( t.remove_returning_len( &1u8 ), ( None, 2 ) );
+    assert_eq!( t.remove_returning_len( &3u8 ), ( Some( 'c' ), 1 ) );
+    assert_eq!( t.remove_returning_len( &200u8 ), ( Some( 'd' ), 0 ) );
+}
+
+#[test]
+fn len_is_tracked() {
+    let mut t = CritBit::new();
+    for i in 0..1000u16 {
+        t.insert( i.wrapping_mul( 7919 ), () );
+        assert_eq!( t.len(), i as usize + 1 );
     }
+    t.insert( 0u16, () );
+    assert_eq!( t.len(), 1000 );
+    assert_eq!( t.len(), t.iter().count() );
+    t.remove( &7919u16 );
+    t.remove( &7919u16 );
+    assert_eq!( t.len(), 999 );
+    t.remove_min_n( 9 );
+    assert_eq!( t.len(), 990 );
+    let low = t.split_off_le( &500u16 );
+    assert_eq!( low.len() + t.len(), 990 );
+    assert_eq!( ( low.len(), t.len() ), ( low.iter().count(), t.iter().count() ) );
+    t.try_retain( |k, _| Ok::<_, ()>( k % 2 == 0 ) ).unwrap();
+    assert_eq!( t.len(), t.iter().count() );
+    t.clear();
+    assert_eq!( t.len(), 0 );
+}
 
-    fn get( &self, key: &K ) -> Option<&V> {
-        match *self {
-            CritBitNode::Leaf ( ref k, ref v ) if *k == *key =>
-                Some( v ),
-            CritBitNode::Internal ( ( Some( ref left ), _ ), ref crit ) if ! bit_at( key, crit ) =>
-                left.get( key ),
-            CritBitNode::Internal ( ( _, Some( ref right ) ), ref crit ) if   bit_at( key, crit ) =>
-                right.get( key ),
-            _ => None
-        }
+#[test]
+fn collect_and_extend() {
+    let mut t : CritBit<u8,u8> = vec![ ( 3, 30 ), ( 1, 10 ), ( 3, 31 ), ( 2, 20 ) ].into_iter().collect();
+    assert_eq!( t.len(), 3 );
+    assert_eq!( t.get( &3 ), Some( &31 ) );
+    t.extend( vec![ ( 4, 40 ), ( 1, 11 ), ( 4, 41 ) ] );
+    assert_eq!( t.len(), 4 );
+    assert_eq!( t.iter().map( |( k, v )| ( *k, *v ) ).collect::<Vec<_>>(), vec![ ( 1, 11 ), ( 2, 20 ), ( 3, 31 ), ( 4, 41 ) ] );
+}
+
+#[test]
+fn range() {
+    let mut t : CritBit<u8,()> = CritBit::new();
+    assert_eq!( t.range( 0..255 ).next(), None );
+    for k in [ 10u8, 20u8, 30u8, 40u8 ].iter() {
+        t.insert( *k, () );
     }
+    let keys = |entries: Entries<u8,()>| entries.map( |( k, _ )| *k ).collect::<Vec<_>>();
+    let rev = |entries: Entries<u8,()>| entries.rev().map( |( k, _ )| *k ).collect::<Vec<_>>();
+    assert_eq!( keys( t.range( 15..35 ) ), vec![ 20, 30 ] );
+    assert_eq!( keys( t.range( 10..40 ) ), vec![ 10, 20, 30 ] );
+    assert_eq!( keys( t.range( 0..255 ) ), vec![ 10, 20, 30, 40 ] );
+    assert_eq!( keys( t.range( 21..29 ) ), vec![] );
+    assert_eq!( keys( t.range( 20..21 ) ), vec![ 20 ] );
+    assert_eq!( keys( t.range( 41..255 ) ), vec![] );
+    assert_eq!( keys( t.range( 0..10 ) ), vec![] );
+    assert_eq!( keys( t.range( ( Bound::Included( 30 ), Bound::Excluded( 20 ) ) ) ), vec![] );
+    assert_eq!( keys( t.range( 20..20 ) ), vec![] );
+    assert_eq!( rev( t.range( 15..35 ) ), vec![ 30, 20 ] );
+    assert_eq!( rev( t.range( 10..40 ) ), vec![ 30, 20, 10 ] );
+    assert_eq!( rev( t.range( 11..255 ) ), vec![ 40, 30, 20 ] );
 
-    fn get_mut( &mut self, key: &K ) -> Option<&mut V> {
-        match *self {
-            CritBitNode::Leaf ( ref k, ref mut v ) if *k == *key =>
-                Some( v ),
-            CritBitNode::Internal ( ( Some( ref mut kid ), _ ), ref crit ) if ! bit_at( key, crit ) =>
-                kid.get_mut( key ),
-            CritBitNode::Internal ( ( _, Some( ref mut kid ) ), ref crit ) if   bit_at( key, crit ) =>
-                kid.get_mut( key ),
-            _ => None
+    let mut both = t.range( 10..41 );
+    assert_eq!( ( both.next(), both.next_back() ), ( Some( ( &10, &() ) ), Some( ( &40, &() ) ) ) );
+    assert_eq!( ( both.next_back(), both.next() ), ( Some( ( &30, &() ) ), Some( ( &20, &() ) ) ) );
+    assert_eq!( ( both.next(), both.next_back() ), ( None, None ) );
+
+    for lo in 0..50u8 {
+        for hi in 0..50u8 {
+            let expected : Vec<u8> = keys( t.iter() ).into_iter().filter( |k| lo <= *k && *k < hi ).collect();
+            assert_eq!( keys( t.range( lo..hi ) ), expected );
+            assert_eq!( rev( t.range( lo..hi ) ), expected.into_iter().rev().collect::<Vec<_>>() );
         }
     }
+}
 
-    fn insert( &mut self, key: K, value: V ) -> Option<V> {
-        match *self {
-            CritBitNode::Leaf ( ref k, ref mut v ) if *k == key => {
-                Some( std::mem::replace( v, value ) )
-            }
-            CritBitNode::Leaf ( .. ) => {
-                if let CritBitNode::Leaf ( k, v ) = std::mem::replace( self, CritBitNode::Internal( ( None, None ), 0 ) ) {
-                    let crit = (k ^ key).leading_zeros();
-                    std::mem::replace(self, CritBitNode::Internal (
-                        if k < key {
-                            (
-                                Some( Box::new( CritBitNode::Leaf ( k, v ) ) ),
-                                Some( Box::new( CritBitNode::Leaf ( key, value ) ) ),
-                            )
-                        } else {
-                            (
-                                Some( Box::new( CritBitNode::Leaf ( key, value ) ) ),
-                                Some( Box::new( CritBitNode::Leaf ( k, v ) ) ),
-                            )
-                        }, crit
-                    ));
-                } else {
-                    unreachable!("We just checked that this was a leaf...")
-                }
-                None
-            },
-            CritBitNode::Internal ( ( Some( ref mut kid ), _ ), ref crit ) if ! bit_at( &key, crit ) =>
-                kid.insert( key, value ),
-            CritBitNode::Internal ( ( _, Some( ref mut kid ) ), ref crit ) if   bit_at( &key, crit ) =>
-                kid.insert( key, value ),
-            _ => unreachable!("Internal nodes should always have both branches filled, what happened?")
+#[test]
+fn entry_counts() {
+    let mut counts = CritBit::new();
+    for word in "the cat and the hat and the bat".split( ' ' ) {
+        *counts.entry( word.as_bytes().to_vec() ).or_insert( 0 ) += 1;
+    }
+    assert_eq!( counts.len(), 5 );
+    let tally = |w: &str| counts.get( &w.as_bytes().to_vec() ).cloned();
+    assert_eq!( ( tally( "the" ), tally( "and" ), tally( "cat" ), tally( "dog" ) ), ( Some( 3 ), Some( 2 ), Some( 1 ), None ) );
+
+    let mut t = CritBit::new();
+    assert_eq!( *t.entry( 5u8 ).or_insert_with( || 'a' ), 'a' );
+    assert_eq!( *t.entry( 5u8 ).or_insert_with( || 'b' ), 'a' );
+    match t.entry( 5u8 ) {
+        Entry::Occupied ( mut e ) => assert_eq!( ( *e.key(), e.insert( 'c' ) ), ( 5u8, 'a' ) ),
+        Entry::Vacant ( _ ) => panic!( "5 is stored" )
+    }
+    match t.entry( 6u8 ) {
+        Entry::Vacant ( e ) => assert_eq!( *e.insert( 'd' ), 'd' ),
+        Entry::Occupied ( _ ) => panic!( "6 isn't stored" )
+    }
+    assert_eq!( t.iter().collect::<Vec<_>>(), vec![ ( &5u8, &'c' ), ( &6u8, &'d' ) ] );
+    assert!( t.is_well_formed() );
+    // Below the root, the entry finds its way back to the leaf each time
+    match t.entry( 6u8 ) {
+        Entry::Occupied ( mut e ) => {
+            *e.get_mut() = 'e';
+            assert_eq!( ( *e.key(), *e.get() ), ( 6u8, 'e' ) );
+        },
+        Entry::Vacant ( _ ) => panic!( "6 is stored" )
+    }
+    assert_eq!( t.get( &6u8 ), Some( &'e' ) );
+}
+
+#[test]
+fn or_try_insert_with() {
+    let mut t = CritBit::new();
+    t.insert( 1u8, 10u32 );
+    assert_eq!( t.entry( 1u8 ).or_try_insert_with( || Err( "unused" ) ).map( |v| *v ), Ok( 10u32 ) );
+    assert_eq!( t.entry( 2u8 ).or_try_insert_with( || Err( "failed" ) ), Err( "failed" ) );
+    assert_eq!( ( t.len(), t.get( &2u8 ) ), ( 1, None ) );
+    assert!( t.is_well_formed() );
+
+    *t.entry( 2u8 ).or_try_insert_with( || Ok::<_, ()>( 20u32 ) ).unwrap() += 1;
+    match t.entry( 3u8 ) {
+        Entry::Vacant ( e ) => assert!( e.or_try_insert_with( || "x".parse::<u32>() ).is_err() ),
+        Entry::Occupied ( _ ) => panic!( "3 isn't stored" )
+    }
+    assert_eq!( t.iter().collect::<Vec<_>>(), vec![ ( &1u8, &10u32 ), ( &2u8, &21u32 ) ] );
+}
+
+#[test]
+fn remove_if() {
+    let mut t = CritBit::new();
+    for k in [ 1u8, 2u8, 3u8, 200u8 ].iter() {
+        t.insert( *k, *k as u32 * 10 );
+    }
+    let remove_if = |t: &mut CritBit<u8,u32>, k: u8, limit: u32| match t.entry( k ) {
+        Entry::Occupied ( e ) => e.remove_if( |v| *v < limit ),
+        Entry::Vacant ( _ ) => panic!( "{} is stored", k )
+    };
+    assert_eq!( remove_if( &mut t, 2u8, 25 ), Some( 20 ) );
+    assert_eq!( remove_if( &mut t, 3u8, 25 ), None );
+    assert_eq!( t.keys().collect::<Vec<_>>(), vec![ &1u8, &3u8, &200u8 ] );
+    assert!( t.is_well_formed() );
+
+    match t.entry( 200u8 ) {
+        Entry::Occupied ( e ) => assert_eq!( e.remove_entry(), ( 200u8, 2000u32 ) ),
+        Entry::Vacant ( _ ) => panic!( "200 is stored" )
+    }
+    for k in [ 1u8, 3u8 ].iter() {
+        assert_eq!( remove_if( &mut t, *k, 100 ), Some( *k as u32 * 10 ) );
+    }
+    assert!( t.is_empty() && t.is_well_formed() );
+}
+
+#[test]
+fn zero_width_keys() {
+    let mut t = CritBit::new();
+    assert_eq!( t.insert( (), 1u8 ), None );
+    assert_eq!( t.insert( (), 2u8 ), Some( 1u8 ) );
+    assert_eq!( ( t.len(), t.get( &() ) ), ( 1, Some( &2u8 ) ) );
+    assert_eq!( t.remove( &() ), Some( 2u8 ) );
+    assert!( t.is_empty() );
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "zero-width keys must all be equal")]
+fn insert_rejects_unsplittable_keys() {
+    // A key type breaking the rule: distinct keys, but no bits to split them on
+    #[derive(PartialEq, Eq)]
+    struct Blank( u8 );
+
+    impl CritKey for Blank {
+        fn crit_bit( &self, other: &Blank ) -> Option<usize> {
+            if self.0 == other.0 { None } else { Some( 0 ) }
+        }
+
+        fn bit_set( &self, _: usize ) -> bool {
+            false
+        }
+
+        fn bit_len( &self ) -> usize {
+            0
         }
     }
+
+    let mut t = CritBit::new();
+    t.insert( Blank( 1 ), () );
+    t.insert( Blank( 2 ), () );
 }
 
 #[test]
-fn verify_bit_at() {
-    assert_eq!( bit_at( &1u8, &0u32 ), false );
-    assert_eq!( bit_at( &128u8, &0u32 ), true );
-    assert_eq!( bit_at( &1u8, &7u32 ), true );
-    assert_eq!( bit_at( &128u8, &7u32 ), false );
+fn insert_entry() {
+    let mut t = CritBit::new();
+    assert_eq!( *t.insert_entry( 5u8, 'a' ).get(), 'a' );
+    for k in [ 1u8, 9u8, 7u8, 130u8 ].iter() {
+        let e = t.insert_entry( *k, 'b' );
+        assert_eq!( ( *e.key(), *e.get() ), ( *k, 'b' ) );
+    }
+    let mut e = t.insert_entry( 7u8, 'c' );
+    assert_eq!( e.insert( 'd' ), 'c' );
+    assert_eq!( e.remove(), 'd' );
+    *t.insert_entry( 8u8, 'e' ).into_mut() = 'f';
+    assert_eq!( t.iter().collect::<Vec<_>>(), vec![ ( &1u8, &'b' ), ( &5u8, &'a' ), ( &8u8, &'f' ), ( &9u8, &'b' ), ( &130u8, &'b' ) ] );
+    assert!( t.is_well_formed() );
 }
 
 #[test]
-fn empty_len() {
-    let t : CritBit<u8,()> = CritBit::new();
-    assert_eq!( t.len(), 0 );
+fn retain() {
+    let mut t = CritBit::new();
+    for k in 0..40u8 {
+        t.insert( k.wrapping_mul( 97 ), k % 7 );
+    }
+    t.retain( |_, v| v % 2 == 0 );
+    assert_eq!( t.len(), ( 0..40u8 ).filter( |k| k % 7 % 2 == 0 ).count() );
+    assert!( t.iter().all( |( _, v )| v % 2 == 0 ) );
+    assert!( ( 0..40u8 ).filter( |k| k % 7 % 2 == 0 ).all( |k| t.contains_key( &k.wrapping_mul( 97 ) ) ) );
+    assert!( t.is_well_formed() );
+    assert_eq!( t.iter_internal().count(), t.len() - 1 );
+    t.retain( |_, _| false );
+    assert!( t.is_empty() && t.is_well_formed() );
 }
 
 #[test]
-fn empty_contains_key() {
-    let t : CritBit<u8,()> = CritBit::new();
-    assert_eq!( t.contains_key( &0u8 ), false );
-    assert_eq!( t.contains_key( &128u8 ), false );
-    assert_eq!( t.contains_key( &255u8 ), false );
+fn successor_predecessor() {
+    let mut t = CritBit::new();
+    assert_eq!( t.successor( &5u8 ), None );
+    for k in [ 10u8, 20u8, 30u8 ].iter() {
+        t.insert( *k, () );
+    }
+    assert_eq!( t.successor( &20 ), Some( ( &30, &() ) ) );
+    assert_eq!( t.predecessor( &20 ), Some( ( &10, &() ) ) );
+    assert_eq!( t.successor( &30 ), None );
+    assert_eq!( t.predecessor( &10 ), None );
+    assert_eq!( t.successor( &5 ), Some( ( &10, &() ) ) );
+    assert_eq!( t.predecessor( &25 ), Some( ( &20, &() ) ) );
+    assert_eq!( t.predecessor( &255 ), Some( ( &30, &() ) ) );
 }
 
 #[test]
-fn empty_get() {
-    let t : CritBit<u8,()> = CritBit::new();
-    assert_eq!( t.get( &0u8 ), None );
-    assert_eq!( t.get( &128u8 ), None );
-    assert_eq!( t.get( &255u8 ), None );
+fn split_off() {
+    let mut t : CritBit<u8,()> = ( 1..6u8 ).map( |k| ( k, () ) ).collect();
+    let high = t.split_off( &3 );
+    assert_eq!( t.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 1, 2 ] );
+    assert_eq!( high.iter().map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 3, 4, 5 ] );
+    assert_eq!( ( t.len(), high.len() ), ( 2, 3 ) );
+    assert!( t.is_well_formed() && high.is_well_formed() );
+
+    let mut all : CritBit<u8,()> = ( 0..=255u8 ).step_by( 3 ).map( |k| ( k, () ) ).collect();
+    let mut rest = all.split_off( &100 );
+    assert!( all.iter().all( |( k, _ )| *k < 100 ) && rest.iter().all( |( k, _ )| *k >= 100 ) );
+    assert_eq!( all.len() + rest.len(), 86 );
+    assert_eq!( rest.split_off( &0 ).len(), 52 );
+    assert!( rest.is_empty() && rest.is_well_formed() );
 }
 
 #[test]
-fn empty_get_mut() {
-    let mut t : CritBit<u8,()> = CritBit::new();
-    assert!( t.get_mut( &0u8 ).is_none() );
-    assert!( t.get_mut( &128u8 ).is_none() );
-    assert!( t.get_mut( &255u8 ).is_none() );
+fn append() {
+    let mut t : CritBit<u8,char> = vec![ ( 3, 'a' ), ( 4, 'a' ), ( 5, 'a' ) ].into_iter().collect();
+    let mut other : CritBit<u8,char> = vec![ ( 1, 'b' ), ( 2, 'b' ), ( 3, 'b' ) ].into_iter().collect();
+    t.append( &mut other );
+    assert!( other.is_empty() );
+    assert_eq!( t.len(), 5 );
+    assert_eq!( t.iter().map( |( k, v )| ( *k, *v ) ).collect::<Vec<_>>(), vec![ ( 1, 'b' ), ( 2, 'b' ), ( 3, 'b' ), ( 4, 'a' ), ( 5, 'a' ) ] );
+
+    // Disjoint halves are spliced under a new root
+    let mut high : CritBit<u8,char> = vec![ ( 200, 'c' ), ( 255, 'c' ) ].into_iter().collect();
+    t.append( &mut high );
+    assert!( high.is_empty() && t.is_well_formed() );
+    assert_eq!( t.len(), 7 );
+    assert_eq!( t.iter_internal().next(), Some( ( 0, 5, 2 ) ) );
 }
 
 #[test]
-fn insert_len() {
-    let mut t : CritBit<u8,()> = CritBit::new();
-    assert_eq!( t.len(), 0 );
+fn keys_and_values() {
+    let mut t : CritBit<u8,u32> = vec![ ( 9, 90 ), ( 2, 20 ), ( 130, 1300 ), ( 40, 400 ) ].into_iter().collect();
+    assert_eq!( t.keys().cloned().collect::<Vec<_>>(), vec![ 2, 9, 40, 130 ] );
+    assert_eq!( t.values().cloned().collect::<Vec<_>>(), vec![ 20, 90, 400, 1300 ] );
+    for v in t.values_mut() {
+        *v += 1;
+    }
+    assert_eq!( t.values().cloned().collect::<Vec<_>>(), vec![ 21, 91, 401, 1301 ] );
+    assert_eq!( CritBit::<u8,u32>::new().keys().next(), None );
+}
 
-    t.insert( 0u8, () );
-    assert_eq!( t.len(), 1 )
+#[test]
+fn into_iter_to_btreemap() {
+    use std::collections::BTreeMap;
+    let pairs = vec![ ( 9u8, 'a' ), ( 2u8, 'b' ), ( 130u8, 'c' ), ( 40u8, 'd' ) ];
+    let t : CritBit<u8,char> = pairs.iter().cloned().collect();
+    assert_eq!( t.into_iter().collect::<Vec<_>>(), vec![ ( 2, 'b' ), ( 9, 'a' ), ( 40, 'd' ), ( 130, 'c' ) ] );
+    let t : CritBit<u8,char> = pairs.iter().cloned().collect();
+    assert_eq!( t.into_iter().collect::<BTreeMap<_,_>>(), pairs.into_iter().collect::<BTreeMap<_,_>>() );
+
+    let t : CritBit<u8,String> = ( 0..10u8 ).map( |k| ( k, k.to_string() ) ).collect();
+    let mut partial = t.into_iter();
+    assert_eq!( partial.next(), Some( ( 0, "0".to_string() ) ) );
+    drop( partial );
 }
 
 #[test]
-fn insert_contains_key() {
-    let mut t : CritBit<u8,()> = CritBit::new();
-    assert_eq!( t.contains_key( &0u8 ), false );
+fn from_sorted() {
+    let pairs : Vec<( u16, u16 )> = ( 0..500u16 ).map( |i| ( i * 131 % 60000, i ) ).collect::<CritBit<_,_>>().into_iter().collect();
+    let built = CritBit::from_sorted( pairs.clone() );
+    let inserted : CritBit<u16,u16> = pairs.iter().cloned().collect();
+    assert!( built.is_well_formed() );
+    assert_eq!( built.len(), 500 );
+    assert!( built == inserted );
+    assert_eq!( format!( "{:?}", built ), format!( "{:?}", inserted ) );
+    assert!( pairs.iter().all( |&( k, v )| built.get( &k ) == Some( &v ) ) );
+
+    assert!( CritBit::<u8,()>::from_sorted( vec![] ).is_empty() );
+    assert_eq!( CritBit::from_sorted( vec![ ( 7u8, () ) ] ).len(), 1 );
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "strictly increasing")]
+fn from_sorted_rejects_unsorted() {
+    CritBit::from_sorted( vec![ ( 2u8, () ), ( 1u8, () ) ] );
+}
+
+#[test]
+fn count_prefix() {
+    let mut t = CritBit::new();
+    for k in [ "car", "cart", "dog" ].iter() {
+        t.insert( k.as_bytes().to_vec(), () );
+    }
+    let count = |p: &str| t.count_prefix( &p.as_bytes().to_vec() );
+    let contains = |p: &str| t.contains_prefix( &p.as_bytes().to_vec() );
+    assert_eq!( ( count( "car" ), count( "ca" ), count( "cart" ), count( "" ) ), ( 2, 2, 1, 3 ) );
+    assert!( contains( "do" ) && contains( "dog" ) );
+    // The descent ends at the lone "dog" leaf, which doesn't have these prefixes
+    assert!( ! contains( "dot" ) && ! contains( "dogs" ) );
+    assert_eq!( count( "x" ), 0 );
+    assert!( ! CritBit::<Vec<u8>,()>::new().contains_prefix( &vec![] ) );
+}
+
+#[test]
+fn get_or_insert_with() {
+    let mut t = CritBit::new();
+    let mut calls = 0;
+    t.get_or_insert_with( 4u8, || { calls += 1; vec![ 'a' ] } ).push( 'b' );
+    assert_eq!( calls, 1 );
+    t.get_or_insert_with( 4u8, || { calls += 1; vec![] } ).push( 'c' );
+    assert_eq!( calls, 1 );
+    assert_eq!( t.get( &4u8 ), Some( &vec![ 'a', 'b', 'c' ] ) );
+    assert_eq!( t.len(), 1 );
+}
 
+#[test]
+fn depth_stats() {
+    let mut t = CritBit::new();
+    assert_eq!( ( t.depth(), t.avg_depth() ), ( 0, 0.0 ) );
     t.insert( 0u8, () );
-    assert_eq!( t.contains_key( &0u8 ), true );
+    assert_eq!( ( t.depth(), t.avg_depth() ), ( 1, 1.0 ) );
+    t.insert( 128u8, () );
+    assert_eq!( ( t.depth(), t.avg_depth() ), ( 2, 2.0 ) );
+
+    let chain : CritBit<u8,()> = vec![ ( 0, () ), ( 1, () ), ( 2, () ) ].into_iter().collect();
+    assert_eq!( chain.depth(), 3 );
+    assert!( ( chain.avg_depth() - 8.0 / 3.0 ).abs() < 1e-9 );
 }
 
 #[test]
-fn insert_get() {
-    let mut t : CritBit<u8,u8> = CritBit::new();
-    assert_eq!( t.get( &0u8 ), None );
+fn pop_first_last() {
+    let mut t : CritBit<u8,char> = vec![ ( 3, 'c' ), ( 1, 'a' ), ( 2, 'b' ) ].into_iter().collect();
+    let mut popped = Vec::new();
+    while let Some( entry ) = t.pop_first() {
+        popped.push( entry );
+    }
+    assert_eq!( popped, vec![ ( 1, 'a' ), ( 2, 'b' ), ( 3, 'c' ) ] );
+    assert!( t.is_empty() );
+    assert_eq!( t.pop_last(), None );
 
-    t.insert( 0u8, 1u8 );
-    assert_eq!( t.get( &0u8 ), Some ( &1u8 ) );
+    t.extend( vec![ ( 3, 'c' ), ( 1, 'a' ), ( 2, 'b' ) ] );
+    assert_eq!( t.pop_last(), Some( ( 3, 'c' ) ) );
+    assert_eq!( t.len(), 2 );
+    assert!( t.is_well_formed() );
 }
 
 #[test]
-fn insert_get_mut() {
-    let mut t : CritBit<u8,u8> = CritBit::new();
-    assert_eq!( t.get( &0u8 ), None );
+fn insert_out_of_order_crit_bits() {
+    // The bits these keys differ at arrive out of order, so new internal nodes have to be spliced
+    // in both above and below the existing ones rather than just above the leaf the search found.
+    let keys = [ 0b0001u8, 0b0100, 0b0010, 0b1000, 0b0011 ];
+    let mut t = CritBit::new();
+    for &k in keys.iter() {
+        assert_eq!( t.insert( k, k ), None );
+        assert!( t.is_well_formed() );
+    }
+    for &k in keys.iter() {
+        assert_eq!( t.get( &k ), Some( &k ) );
+    }
+    let mut sorted = keys.to_vec();
+    sorted.sort();
+    assert_eq!( t.keys().cloned().collect::<Vec<_>>(), sorted );
+}
 
-    t.insert( 0u8, 1u8 );
-    assert_eq!( t.get( &0u8 ), Some ( &1u8 ) );
+#[test]
+fn for_loop_by_ref() {
+    let t : CritBit<u16,u16> = vec![ ( 300, 3 ), ( 2, 1 ), ( 40, 2 ) ].into_iter().collect();
+    let mut seen = Vec::new();
+    for ( k, v ) in &t {
+        seen.push( ( *k, *v ) );
+    }
+    assert_eq!( seen, vec![ ( 2, 1 ), ( 40, 2 ), ( 300, 3 ) ] );
+    assert_eq!( ( &t ).into_iter().next_back(), Some( ( &300, &3 ) ) );
+}
 
-    *t.get_mut( &0u8 ).unwrap() = 2u8;
-    assert_eq!( t.get( &0u8 ), Some ( &2u8 ) );
+#[test]
+fn for_loop_by_mut() {
+    let mut t : CritBit<u8,u32> = ( 0..20u8 ).map( |k| ( k, u32::from( k ) * 10 ) ).collect();
+    for ( k, v ) in &mut t {
+        *v -= u32::from( *k );
+    }
+    assert!( t.iter().all( |( k, v )| *v == u32::from( *k ) * 9 ) );
+    assert!( t.is_well_formed() );
 }
 
 #[test]
-fn insert_insert() {
-    let mut t : CritBit<u8,u8> = CritBit::new();
-    assert_eq!( t.get( &0u8 ), None );
+fn keys_values_both_ends() {
+    let t : CritBit<u8,char> = vec![ ( 1, 'a' ), ( 2, 'b' ), ( 3, 'c' ), ( 4, 'd' ) ].into_iter().collect();
+    assert_eq!( t.keys().rev().cloned().collect::<Vec<_>>(), vec![ 4, 3, 2, 1 ] );
+    let mut values = t.values();
+    assert_eq!( values.next(), Some( &'a' ) );
+    assert_eq!( values.next_back(), Some( &'d' ) );
+    assert_eq!( values.collect::<String>(), "bc" );
+}
 
-    t.insert( 0u8, 1u8 );
-    assert_eq!( t.get( &0u8 ), Some ( &1u8 ) );
+#[test]
+fn range_bounds() {
+    let t : CritBit<u8,u8> = ( 0..10u8 ).map( |k| ( k * 5, k ) ).collect();
+    let keys = |entries: Entries<u8,u8>| entries.map( |( k, _ )| *k ).collect::<Vec<_>>();
+    assert_eq!( keys( t.range( .. ) ), keys( t.iter() ) );
+    for lo in 0..50u8 {
+        assert_eq!( keys( t.range( lo.. ) ), keys( t.iter() ).into_iter().filter( |k| lo <= *k ).collect::<Vec<_>>() );
+        assert_eq!( keys( t.range( ..=lo ) ), keys( t.iter() ).into_iter().filter( |k| *k <= lo ).collect::<Vec<_>>() );
+        for hi in 0..50u8 {
+            let bounds = ( Bound::Excluded( lo ), Bound::Included( hi ) );
+            let expected : Vec<u8> = keys( t.iter() ).into_iter().filter( |k| lo < *k && *k <= hi ).collect();
+            assert_eq!( keys( t.range( bounds ) ), expected );
+            assert_eq!( t.range( bounds ).rev().map( |( k, _ )| *k ).collect::<Vec<_>>(), expected.into_iter().rev().collect::<Vec<_>>() );
+            assert_eq!( keys( t.range( lo..=hi ) ).len(), ( lo..=hi ).filter( |k| k % 5 == 0 ).count() );
+        }
+    }
+}
 
-    assert_eq!( t.insert( 0u8, 2u8 ), Some ( 1u8 ) );
-    assert_eq!( t.get( &0u8 ), Some ( &2u8 ) );
+#[test]
+fn range_mut() {
+    let mut t : CritBit<u8,u8> = ( 0..10u8 ).map( |k| ( k * 5, k ) ).collect();
+    for ( _, v ) in t.range_mut( 12..=30 ) {
+        *v += 100;
+    }
+    assert_eq!( t.values().cloned().collect::<Vec<_>>(), vec![ 0, 1, 2, 103, 104, 105, 106, 7, 8, 9 ] );
+    assert_eq!( t.range_mut( ( Bound::Excluded( 40 ), Bound::Unbounded ) ).map( |( k, _ )| *k ).collect::<Vec<_>>(), vec![ 45 ] );
+    assert_eq!( t.range_mut( ..5 ).count(), 1 );
+    assert_eq!( t.range_mut( 31..35 ).count(), 0 );
+    assert_eq!( t.range_mut( ( Bound::Included( 30 ), Bound::Excluded( 12 ) ) ).next(), None );
+}
+
+#[test]
+fn iter_prefix_bits() {
+    let ip = |a: u32, b: u32, c: u32, d: u32| a << 24 | b << 16 | c << 8 | d;
+    let t : CritBit<u32,()> = vec![ ip( 10, 0, 0, 1 ), ip( 10, 0, 1, 5 ), ip( 10, 1, 0, 0 ), ip( 11, 0, 0, 0 ), ip( 192, 168, 0, 1 ) ]
+        .into_iter().map( |k| ( k, () ) ).collect();
+    let subnet = |net: u32, len: usize| t.iter_prefix_bits( &net, len ).map( |( k, _ )| *k ).collect::<Vec<_>>();
+    assert_eq!( subnet( ip( 10, 0, 0, 0 ), 16 ), vec![ ip( 10, 0, 0, 1 ), ip( 10, 0, 1, 5 ) ] );
+    assert_eq!( subnet( ip( 10, 0, 0, 0 ), 8 ), vec![ ip( 10, 0, 0, 1 ), ip( 10, 0, 1, 5 ), ip( 10, 1, 0, 0 ) ] );
+    assert_eq!( subnet( ip( 10, 0, 0, 0 ), 7 ).len(), 4 );
+    assert_eq!( subnet( ip( 10, 0, 0, 1 ), 32 ), vec![ ip( 10, 0, 0, 1 ) ] );
+    assert_eq!( subnet( ip( 10, 0, 0, 2 ), 64 ), vec![] );
+    assert_eq!( subnet( ip( 172, 16, 0, 0 ), 12 ), vec![] );
+    assert_eq!( subnet( 0, 0 ).len(), 5 );
+
+    let words : CritBit<&[u8],()> = vec![ &b"cab"[ .. ], b"cat", b"cot", b"dog" ].into_iter().map( |k| ( k, () ) ).collect();
+    // `a` and `o` share their top four bits, so half a symbol past the `c` still matches both
+    let half = words.iter_prefix_bits( &&b"ca"[ .. ], 9 + 5 ).map( |( k, _ )| *k ).collect::<Vec<_>>();
+    assert_eq!( half, vec![ &b"cab"[ .. ], b"cat", b"cot" ] );
+    assert_eq!( words.iter_prefix_bits( &&b"ca"[ .. ], 18 ).count(), 2 );
+}
+
+#[test]
+fn find_longest_prefix() {
+    let ip = |a: u32, b: u32, c: u32, d: u32| a << 24 | b << 16 | c << 8 | d;
+    let mut routes = CritBit::new();
+    assert_eq!( routes.find_longest_prefix( ip( 10, 1, 2, 3 ) ), None );
+    routes.insert( Prefix::new( ip( 10, 0, 0, 0 ), 8 ), "ten" );
+    routes.insert( Prefix::new( ip( 10, 1, 0, 0 ), 16 ), "ten-one" );
+    routes.insert( Prefix::new( ip( 192, 168, 1, 0 ), 24 ), "lan" );
+    routes.insert( Prefix::new( ip( 10, 1, 2, 3 ), 32 ), "host" );
+    assert!( routes.is_well_formed() );
+    let route = |routes: &CritBit<Prefix<u32>,&'static str>, addr: u32| routes.find_longest_prefix( addr ).map( |( k, len, v )| ( k.bits(), len, *v ) );
+    assert_eq!( route( &routes, ip( 10, 1, 2, 3 ) ), Some( ( ip( 10, 1, 2, 3 ), 32, "host" ) ) );
+    assert_eq!( route( &routes, ip( 10, 1, 2, 4 ) ), Some( ( ip( 10, 1, 0, 0 ), 16, "ten-one" ) ) );
+    assert_eq!( route( &routes, ip( 10, 200, 0, 1 ) ), Some( ( ip( 10, 0, 0, 0 ), 8, "ten" ) ) );
+    assert_eq!( route( &routes, ip( 192, 168, 1, 77 ) ), Some( ( ip( 192, 168, 1, 0 ), 24, "lan" ) ) );
+    assert_eq!( route( &routes, ip( 192, 168, 2, 1 ) ), None );
+
+    routes.insert( Prefix::new( 0, 0 ), "default" );
+    assert_eq!( route( &routes, ip( 192, 168, 2, 1 ) ), Some( ( 0, 0, "default" ) ) );
+    // Host bits past the prefix are cleared, and shorter prefixes sort first
+    assert_eq!( Prefix::new( ip( 10, 9, 9, 9 ), 8 ), Prefix::new( ip( 10, 0, 0, 0 ), 8 ) );
+    assert_eq!( routes.values().cloned().collect::<Vec<_>>(), vec![ "default", "ten", "ten-one", "host", "lan" ] );
+}
+
+#[test]
+fn aggregate_prefixes() {
+    let ip = |a: u32, b: u32, c: u32, d: u32| a << 24 | b << 16 | c << 8 | d;
+    let mut routes = CritBit::new();
+    routes.insert( Prefix::new( ip( 10, 0, 0, 0 ), 8 ), "ten" );
+    routes.insert( Prefix::new( ip( 10, 1, 0, 0 ), 16 ), "ten-one" );
+    routes.insert( Prefix::new( ip( 10, 1, 2, 0 ), 24 ), "ten-one-two" );
+    routes.insert( Prefix::new( ip( 11, 0, 0, 0 ), 8 ), "eleven" );
+    routes.insert( Prefix::new( ip( 192, 168, 1, 0 ), 24 ), "lan" );
+    routes.insert( Prefix::new( ip( 192, 168, 2, 0 ), 24 ), "lab" );
+    assert_eq!( routes.aggregate_prefixes(), 2 );
+    assert!( routes.is_well_formed() );
+    assert_eq!( routes.values().cloned().collect::<Vec<_>>(), vec![ "ten", "eleven", "lan", "lab" ] );
+    assert_eq!( routes.aggregate_prefixes(), 0 );
+
+    let mut words : CritBit<&[u8],()> = vec![ &b"ab"[ .. ], b"abc", b"abd", b"b" ].into_iter().map( |k| ( k, () ) ).collect();
+    assert_eq!( words.aggregate_prefixes(), 2 );
+    assert_eq!( words.keys().cloned().collect::<Vec<_>>(), vec![ &b"ab"[ .. ], b"b" ] );
+    let mut ints : CritBit<u8,()> = ( 0..10 ).map( |k| ( k, () ) ).collect();
+    assert_eq!( ints.aggregate_prefixes(), 0 );
+}
+
+#[test]
+fn signed_keys_in_numeric_order() {
+    let mut t : CritBit<i32,()> = vec![ 3, -1, 0, -5 ].into_iter().map( |k| ( k, () ) ).collect();
+    let keys = |entries: Entries<i32,()>| entries.map( |( k, _ )| *k ).collect::<Vec<_>>();
+    assert_eq!( keys( t.iter() ), vec![ -5, -1, 0, 3 ] );
+    assert_eq!( keys( t.range( -5..5 ) ), vec![ -5, -1, 0, 3 ] );
+    assert_eq!( keys( t.range( -2..=0 ) ), vec![ -1, 0 ] );
+    assert_eq!( ( t.min(), t.max() ), ( Some( ( &-5, &() ) ), Some( ( &3, &() ) ) ) );
+    assert_eq!( t.successor( &-1 ), Some( ( &0, &() ) ) );
+    assert_eq!( t.predecessor( &0 ), Some( ( &-1, &() ) ) );
+    assert_eq!( t.get( &-5 ), Some( &() ) );
+
+    let high = t.split_off( &0 );
+    assert_eq!( keys( t.iter() ), vec![ -5, -1 ] );
+    assert_eq!( keys( high.iter() ), vec![ 0, 3 ] );
+    assert!( t.is_well_formed() && high.is_well_formed() );
+
+    let extremes : CritBit<i8,()> = vec![ ( i8::MAX, () ), ( i8::MIN, () ), ( 0, () ), ( -1, () ) ].into_iter().collect();
+    assert_eq!( extremes.keys().cloned().collect::<Vec<_>>(), vec![ i8::MIN, -1, 0, i8::MAX ] );
+}
+
+#[test]
+fn keys_need_not_be_debug() {
+    #[derive(PartialEq, Eq)]
+    struct Id ( u16 );
+    impl CritKey for Id {
+        fn crit_bit( &self, other: &Id ) -> Option<usize> { self.0.crit_bit( &other.0 ) }
+        fn bit_set( &self, pos: usize ) -> bool { self.0.bit_set( pos ) }
+        fn bit_len( &self ) -> usize { self.0.bit_len() }
+    }
+    let mut t = CritBit::new();
+    t.insert( Id ( 3 ), 'c' );
+    t.insert( Id ( 1 ), 'a' );
+    assert_eq!( t.remove( &Id ( 3 ) ), Some( 'c' ) );
+    assert_eq!( t.get( &Id ( 1 ) ), Some( &'a' ) );
+}
+
+#[test]
+fn values_mut_both_ends() {
+    let mut t : CritBit<u8,u32> = ( 0..40 ).map( |k| ( k, 0 ) ).collect();
+    for ( n, v ) in t.values_mut().rev().enumerate() {
+        *v = n as u32;
+    }
+    assert!( t.iter().all( |( k, v )| *v == 39 - u32::from( *k ) ) );
+    let mut entries = t.iter_mut();
+    let mut seen = Vec::new();
+    for step in 0..40 {
+        let ( k, _ ) = if step % 3 == 0 { entries.next_back() } else { entries.next() }.unwrap();
+        seen.push( *k );
+    }
+    assert_eq!( entries.next(), None );
+    assert_eq!( entries.next_back(), None );
+    seen.sort();
+    assert_eq!( seen, ( 0..40 ).collect::<Vec<_>>() );
+    assert_eq!( t.values_mut().next_back().map( |v| *v ), Some( 0 ) );
 }